@@ -1,86 +1,374 @@
+mod output;
+mod walk;
+
 use anyhow::Result;
 use clap::Parser;
 use config::Config;
-use img_processor::{DefaultImageProcessorFactory, ImageProcessorFactory, Quality};
-use std::{io::BufRead, path::Path};
-use tempfile::Builder;
+use img_processor::{
+    ChromaSubsampling, DefaultImageProcessorFactory, ImageProcessor, ImageProcessorFactory,
+    LargerOutput, Limits, MetadataPolicy, OutputFormat, PendingOutput, Quality, QualityTarget,
+    Resize, ResizeFilter, ResizeMode, ShrinkOptions, ShrinkOutcome, ShrinkReport, Speed,
+};
+use output::{Collision, Outputs};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{self, BufRead},
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Mutex, mpsc},
+    thread,
+};
 use tracing::{Level, event, instrument};
 use tracing_subscriber::{
     EnvFilter,
     fmt::{self, format::FmtSpan},
     prelude::*,
 };
+use walk::{Input, WalkError, Walker, output_name};
 
-#[instrument(skip(factory))]
+/// Image shrunk by a worker, waiting for its output path to be claimed
+struct Shrunk {
+    report: ShrinkReport,
+    /// Path the image was shrunk for along with the output, unless nothing is written
+    output: Option<(PathBuf, PendingOutput)>,
+}
+
+#[instrument(skip(processor))]
 fn shrink_image(
-    factory: &impl ImageProcessorFactory,
-    input_path: &Path,
+    processor: &dyn ImageProcessor,
+    name: &Path,
     output_dir: &Path,
-    quality: Quality,
-) -> Result<()> {
-    let name = input_path
-        .file_name()
-        .ok_or_else(|| anyhow::anyhow!("Invalid input path"))?;
-    let output_path = Path::new(output_dir).join(name);
-    let processor = factory.process_image(input_path)?;
-    processor.shrink_to(&output_path, quality)?;
+    options: &ShrinkOptions,
+) -> Result<Shrunk> {
+    let mut output_path = output_dir.join(name);
+    if let Some(format) = options.format {
+        output_path.set_extension(format.extension());
+    }
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let (report, output) = processor.shrink_pending(&output_path, options)?;
+    let (width, height) = report.input_dimensions;
     event!(
         Level::INFO,
-        "Image processed and saved to: {}",
-        output_path.display()
+        "Decoded {}x{} {:?} image of {} bytes in {:?}",
+        width,
+        height,
+        report.color_type,
+        report.input_size,
+        report.decode_time
     );
-    Ok(())
+    let (width, height) = report.output_dimensions;
+    event!(
+        Level::INFO,
+        "Encoded {}x{} {:?} image of {} bytes with quality {} in {:?}",
+        width,
+        height,
+        report.format,
+        report.output_size,
+        report.quality,
+        report.encode_time
+    );
+    if let Some(ssim) = report.ssim {
+        event!(Level::INFO, "Structural similarity: {:.4}", ssim);
+    }
+    if report.outcome == ShrinkOutcome::NotSmaller {
+        event!(
+            Level::INFO,
+            "Skipped, the output is not smaller than the input"
+        );
+    }
+    Ok(Shrunk {
+        report,
+        output: output.map(|output| (output_path, output)),
+    })
+}
+
+/// Writes a shrunk image to the output path it claims, in input order
+fn save(outputs: &mut Outputs, shrunk: Shrunk) -> Result<Option<ShrinkReport>> {
+    let Shrunk { report, output } = shrunk;
+    let Some((path, output)) = output else {
+        return Ok(Some(report));
+    };
+    let Some(output_path) = outputs.claim(&path)? else {
+        event!(
+            Level::INFO,
+            "Skipped, the output file already exists: {}",
+            path.display()
+        );
+        return Ok(None);
+    };
+    output.persist(&output_path)?;
+    match report.outcome {
+        ShrinkOutcome::CopiedOriginal => event!(
+            Level::INFO,
+            "Output not smaller than the input, original copied to: {}",
+            output_path.display()
+        ),
+        _ => event!(
+            Level::INFO,
+            "Image processed and saved to: {}",
+            output_path.display()
+        ),
+    }
+    Ok(Some(report))
 }
 
-#[instrument(skip(factory, output_dir))]
+/// Image handed to the compression workers
+enum Image {
+    /// Local file, read by the processor, with its output name when found in a directory
+    File {
+        path: PathBuf,
+        name: Option<PathBuf>,
+    },
+    /// Downloaded image held in memory, named after the last segment of its URL
+    Downloaded { name: OsString, bytes: Vec<u8> },
+}
+
+/// Inputs listed one per line, skipping the lines that are not UTF-8. Reading stops at other
+/// errors, which would repeat on every further read.
+fn input_lines(reader: impl BufRead) -> impl Iterator<Item = String> {
+    reader
+        .lines()
+        .map_while(|line| match line {
+            Ok(line) => Some(Some(line)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                event!(Level::WARN, "Skipping input line: {}", e);
+                Some(None)
+            }
+            Err(e) => {
+                event!(Level::ERROR, "Failed to read the input list: {}", e);
+                None
+            }
+        })
+        .flatten()
+}
+
+fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+#[instrument(skip(client))]
+fn download(client: &reqwest::blocking::Client, url: &str) -> Result<Image> {
+    let response = client.get(url).send()?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!("Failed to fetch image from URL: {}", url));
+    }
+    let name = response
+        .url()
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(OsString::from)
+        .ok_or_else(|| anyhow::anyhow!("Invalid input URL"))?;
+    let bytes = Vec::from(response.bytes()?);
+    event!(Level::INFO, "Downloaded {} bytes", bytes.len());
+    Ok(Image::Downloaded { name, bytes })
+}
+
+#[instrument(skip(factory, image, output_dir))]
 fn process_image(
     factory: &impl ImageProcessorFactory,
     input_path: &str,
+    image: Image,
     output_dir: &Path,
-    quality: Quality,
-) -> Result<()> {
-    if input_path.starts_with("http://") || input_path.starts_with("https://") {
-        // Handle remote image processing
-        let response = reqwest::blocking::get(input_path)?;
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Failed to fetch image from URL: {}",
-                input_path
-            ));
+    options: &ShrinkOptions,
+) -> Result<Shrunk> {
+    match image {
+        Image::Downloaded { name, bytes } => {
+            // Handle remote image processing, entirely in memory
+            let processor = factory.process_bytes(&bytes)?;
+            shrink_image(processor.as_ref(), Path::new(&name), output_dir, options)
+        }
+        Image::File { path, name } => {
+            // Handle local image processing
+            let name = name
+                .or_else(|| output_name(&path))
+                .ok_or_else(|| anyhow::anyhow!("Invalid input path"))?;
+            let processor = factory.process_image(&path)?;
+            shrink_image(processor.as_ref(), &name, output_dir, options)
+        }
+    }
+}
+
+/// Totals over all processed images
+#[derive(Debug, Default)]
+struct Statistics {
+    processed: usize,
+    skipped: usize,
+    failed: usize,
+    input_bytes: u64,
+    written_bytes: u64,
+}
+
+impl Statistics {
+    fn add(&mut self, input: &str, result: Result<Option<ShrinkReport>>) {
+        match result {
+            Ok(None) => self.skipped += 1,
+            Ok(Some(report)) if report.outcome == ShrinkOutcome::NotSmaller => self.skipped += 1,
+            Ok(Some(report)) => {
+                self.processed += 1;
+                self.input_bytes += report.input_size;
+                self.written_bytes += report.written_size();
+            }
+            Err(e) => {
+                self.failed += 1;
+                eprintln!("Error processing image {}: {:#}", input, e);
+            }
         }
-        let bytes = response.bytes()?;
-        let mut temp_file = Builder::new()
-            .prefix("img_compactor_")
-            .suffix(".jpg")
-            .tempfile()?;
-        temp_file.disable_cleanup(true);
-        let temp_path = temp_file.path();
-        event!(
-            Level::INFO,
-            "Temporary file created at: {}",
-            temp_path.display()
-        );
-        std::fs::write(temp_path, bytes)?;
-        shrink_image(factory, temp_path, output_dir, quality)
-    } else {
-        // Handle local image processing
-        let input_path = Path::new(input_path);
-        shrink_image(factory, input_path, output_dir, quality)
     }
 }
 
-fn process_files<F: ImageProcessorFactory, I: Iterator<Item = String>>(
+/// Receives from a queue shared by several workers, holding the lock only while waiting
+fn receive<T>(receiver: &Mutex<mpsc::Receiver<T>>) -> Result<T, mpsc::RecvError> {
+    receiver.lock().unwrap().recv()
+}
+
+/// Runs a worker task, turning a panic into an error so the input still gets a result
+fn catch_panic<T>(task: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(task))
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Processing the image panicked")))
+}
+
+/// Processes the inputs in a pipeline: URLs are fetched by a pool of download threads,
+/// which feed the downloaded images to a pool of compression threads along with the local
+/// files. Results are collected in input order, so errors are reported and output paths
+/// claimed the same way whatever the number of jobs. Each result waiting for an earlier
+/// one holds a temporary file, so the inputs are held back while too many wait behind a
+/// slow one.
+fn process_files<F: ImageProcessorFactory + Sync, I: Iterator<Item = Result<Input, WalkError>>>(
     factory: &F,
     input_files: I,
-    output_dir: &Path,
-    quality: Quality,
+    outputs: &mut Outputs,
+    options: &ShrinkOptions,
+    jobs: NonZeroUsize,
+    downloads: NonZeroUsize,
+    statistics: &mut Statistics,
 ) {
-    for input in input_files {
-        event!(Level::INFO, "Processing image: {}", input);
-        if let Err(e) = process_image(factory, &input, output_dir, quality) {
-            eprintln!("Error processing image {}: {}", input, e);
+    let client = reqwest::blocking::Client::new();
+    // The bounded queues hold back the inputs until a worker is free, so downloads only
+    // run ahead of the compression by as many images as there are workers
+    let (url_sender, url_receiver) = mpsc::sync_channel::<(usize, String)>(downloads.get());
+    let url_receiver = Mutex::new(url_receiver);
+    let (image_sender, image_receiver) = mpsc::sync_channel::<(usize, String, Image)>(jobs.get());
+    let image_receiver = Mutex::new(image_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+    let output_dir = &outputs.dir().to_path_buf();
+    thread::scope(|scope| {
+        for _ in 0..downloads.get() {
+            let (url_receiver, client) = (&url_receiver, &client);
+            let image_sender = image_sender.clone();
+            let result_sender = result_sender.clone();
+            scope.spawn(move || {
+                while let Ok((index, url)) = receive(url_receiver) {
+                    let sent = match catch_panic(|| download(client, &url)) {
+                        Ok(image) => image_sender.send((index, url, image)).is_ok(),
+                        Err(e) => result_sender.send((index, url, Err(e))).is_ok(),
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            });
         }
-    }
+        for _ in 0..jobs.get() {
+            let image_receiver = &image_receiver;
+            let result_sender = result_sender.clone();
+            scope.spawn(move || {
+                while let Ok((index, input, image)) = receive(image_receiver) {
+                    event!(Level::INFO, "Processing image: {}", input);
+                    let result =
+                        catch_panic(|| process_image(factory, &input, image, output_dir, options));
+                    if result_sender.send((index, input, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        // Returns the number of inputs saved so far
+        let mut collect = |(index, input, result): (usize, String, Result<Shrunk>)| {
+            pending.insert(index, (input, result));
+            while let Some((input, result)) = pending.remove(&next) {
+                statistics.add(&input, result.and_then(|shrunk| save(outputs, shrunk)));
+                next += 1;
+            }
+            next
+        };
+        let window = 2 * (jobs.get() + downloads.get());
+        let mut saved = 0;
+        for (index, input) in input_files.enumerate() {
+            while index >= saved + window {
+                match result_receiver.recv() {
+                    Ok(result) => saved = collect(result),
+                    Err(_) => break,
+                }
+            }
+            let Input { path, name } = match input {
+                Ok(input) => input,
+                Err(WalkError { input, error }) => {
+                    let error = anyhow::Error::new(error).context("Failed to walk the directory");
+                    saved = collect((index, input, Err(error)));
+                    continue;
+                }
+            };
+            let sent = if is_url(&path) {
+                url_sender.send((index, path)).is_ok()
+            } else {
+                let image = Image::File {
+                    path: PathBuf::from(&path),
+                    name,
+                };
+                image_sender.send((index, path, image)).is_ok()
+            };
+            if !sent {
+                break;
+            }
+            for result in result_receiver.try_iter() {
+                saved = collect(result);
+            }
+        }
+        drop(url_sender);
+        drop(image_sender);
+        for result in result_receiver.iter() {
+            collect(result);
+        }
+        // Inputs left without a result fail, the results after them are still saved
+        for (index, (input, result)) in std::mem::take(&mut pending) {
+            for missing in next..index {
+                let error = anyhow::anyhow!("No result for input number {}", missing + 1);
+                statistics.add("unknown", Err(error));
+            }
+            statistics.add(&input, result.and_then(|shrunk| save(outputs, shrunk)));
+            next = index + 1;
+        }
+    });
+}
+
+/// Parses a byte size with an optional decimal (kB, MB) or binary (KiB, MiB) unit suffix
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1024,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1024 * 1024,
+        _ => return Err(anyhow::anyhow!("Unknown size unit: {}", unit)),
+    };
+    let number: u64 = number.parse()?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Size too large: {}", size))
 }
 
 /// Command-line interface for the image compactor
@@ -90,7 +378,7 @@ struct Cli {
     /// File path to read input paths from
     #[arg(long, value_name = "FILE")]
     from_file: Option<String>,
-    /// The input image file paths or URLs (JPEG)
+    /// The input image file paths, directories or URLs (JPEG, PNG)
     input: Vec<String>,
     /// Reading EOL separated list of files from stdin, finish with Ctrl+D
     #[arg(long)]
@@ -101,6 +389,85 @@ struct Cli {
     /// Quality of the output images (0-100)
     #[arg(long, value_name = "QUALITY")]
     quality: Option<u64>,
+    /// Encoding speed of AVIF output (1-10, slower is smaller)
+    #[arg(long, value_name = "SPEED")]
+    speed: Option<u64>,
+    /// Maximum size of the output images (e.g. 200KiB), searches for the highest quality fitting
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    target_size: Option<u64>,
+    /// Minimum structural similarity (SSIM, 0-1) to the input, searches for the lowest quality reaching it
+    #[arg(long, value_name = "SSIM", conflicts_with = "target_size")]
+    min_ssim: Option<f64>,
+    /// Format of the output images (jpeg, png, webp, webp-lossless, avif), defaults to the input format
+    #[arg(long, value_name = "FORMAT")]
+    format: Option<OutputFormat>,
+    /// Maximum width of the output images in pixels
+    #[arg(long, value_name = "PIXELS")]
+    max_width: Option<u32>,
+    /// Maximum height of the output images in pixels
+    #[arg(long, value_name = "PIXELS")]
+    max_height: Option<u32>,
+    /// How images are fitted into the maximum dimensions (fit, fill, exact)
+    #[arg(long, value_name = "MODE")]
+    resize_mode: Option<ResizeMode>,
+    /// Resampling filter used for resizing (nearest, triangle, catmull-rom, gaussian, lanczos3)
+    #[arg(long, value_name = "FILTER")]
+    resize_filter: Option<ResizeFilter>,
+    /// Metadata carried over (keep, strip, allow:<tags> or deny:<tags>, e.g. deny:GPS*,SerialNumber)
+    #[arg(long, value_name = "POLICY")]
+    metadata: Option<MetadataPolicy>,
+    /// Convert the pixels to sRGB and drop the ICC profile instead of carrying it over
+    #[arg(long)]
+    convert_to_srgb: bool,
+    /// Encode JPEG output progressively
+    #[arg(long)]
+    progressive: bool,
+    /// Chroma subsampling of JPEG output (4:4:4, 4:2:2, 4:2:0)
+    #[arg(long, value_name = "RATIO")]
+    chroma_subsampling: Option<ChromaSubsampling>,
+    /// Optimize JPEG input without re-encoding (Huffman tables, progressive scans, metadata)
+    #[arg(long)]
+    lossless: bool,
+    /// What to do when the output is not smaller than the input (keep, copy, skip)
+    #[arg(long, value_name = "POLICY")]
+    larger_output: Option<LargerOutput>,
+    /// What to do when an output file already exists or an earlier input has the same output
+    /// (overwrite, skip, error, rename, rename-within-run), renaming adds a numeric suffix.
+    /// Defaults to rename-within-run, renaming outputs of the same run but replacing files
+    /// from earlier runs
+    #[arg(long, value_name = "POLICY")]
+    collision: Option<Collision>,
+    /// Reject input images wider than this many pixels before decoding them
+    #[arg(long, value_name = "PIXELS")]
+    limit_width: Option<u32>,
+    /// Reject input images higher than this many pixels before decoding them
+    #[arg(long, value_name = "PIXELS")]
+    limit_height: Option<u32>,
+    /// Reject input images with more pixels than this before decoding them
+    #[arg(long, value_name = "COUNT")]
+    limit_pixels: Option<u64>,
+    /// Maximum memory decoding an input image may allocate (e.g. 512MiB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    limit_alloc: Option<u64>,
+    /// Number of images processed concurrently, defaults to the number of CPUs
+    #[arg(long, short, value_name = "COUNT")]
+    jobs: Option<NonZeroUsize>,
+    /// Number of URLs downloaded concurrently, feeding the jobs
+    #[arg(long, value_name = "COUNT")]
+    downloads: Option<NonZeroUsize>,
+    /// Glob pattern of the files picked from input directories, relative to them and
+    /// case-insensitive (e.g. 'photos/**/*.jpg'), repeatable, defaults to JPEG and PNG files
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Glob pattern of files and directories skipped in input directories, repeatable
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Follow symbolic links when walking input directories
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Include hidden files and directories when walking input directories
+    #[arg(long)]
+    hidden: bool,
 }
 
 fn main() -> Result<()> {
@@ -120,7 +487,7 @@ fn main() -> Result<()> {
         .add_source(config::File::with_name("config.toml").required(false))
         .build()?;
 
-    let factory = DefaultImageProcessorFactory {};
+    let factory = DefaultImageProcessorFactory::default();
     let output_dir = cli.output_dir.unwrap_or_else(|| {
         config
             .get_string("output_dir")
@@ -132,13 +499,193 @@ fn main() -> Result<()> {
     let quality = cli.quality.unwrap_or_else(|| {
         config
             .get_int("quality")
-            .unwrap_or_else(|_| DEFAULT_QUALITY as i64)
+            .unwrap_or(DEFAULT_QUALITY as i64)
             .try_into()
             .unwrap_or(DEFAULT_QUALITY)
     });
     event!(Level::INFO, "Image quality: {}", quality);
     let quality = Quality::try_from(quality)?;
-    process_files(&factory, cli.input.into_iter(), output_dir, quality);
+    let format = match cli.format {
+        Some(format) => Some(format),
+        None => config
+            .get_string("format")
+            .ok()
+            .map(|format| format.parse::<OutputFormat>())
+            .transpose()?,
+    };
+    if let Some(format) = format {
+        event!(Level::INFO, "Output format: {:?}", format);
+    }
+    let speed = cli
+        .speed
+        .or_else(|| config.get::<u64>("speed").ok())
+        .map(Speed::try_from)
+        .transpose()?
+        .unwrap_or_default();
+    event!(Level::INFO, "Encoding speed: {:?}", speed);
+    let max_width = cli
+        .max_width
+        .or_else(|| config.get::<u32>("max_width").ok());
+    let max_height = cli
+        .max_height
+        .or_else(|| config.get::<u32>("max_height").ok());
+    let resize = if max_width.is_some() || max_height.is_some() {
+        let mode = match cli.resize_mode {
+            Some(mode) => mode,
+            None => config
+                .get_string("resize_mode")
+                .map_or(Ok(ResizeMode::default()), |mode| mode.parse())?,
+        };
+        let filter = match cli.resize_filter {
+            Some(filter) => filter,
+            None => config
+                .get_string("resize_filter")
+                .map_or(Ok(ResizeFilter::default()), |filter| filter.parse())?,
+        };
+        let resize = Resize::new(max_width, max_height)
+            .with_mode(mode)
+            .with_filter(filter);
+        event!(Level::INFO, "Resize: {:?}", resize);
+        Some(resize)
+    } else {
+        None
+    };
+    let target_size = match cli.target_size {
+        Some(size) => Some(size),
+        None => config
+            .get_string("target_size")
+            .ok()
+            .map(|size| parse_size(&size))
+            .transpose()?,
+    };
+    let min_ssim = cli.min_ssim.or_else(|| config.get::<f64>("min_ssim").ok());
+    let target = match (target_size, min_ssim) {
+        (Some(size), _) => {
+            event!(Level::INFO, "Target size: {} bytes", size);
+            Some(QualityTarget::MaxSize(size))
+        }
+        (None, Some(ssim)) => {
+            event!(Level::INFO, "Minimum structural similarity: {}", ssim);
+            Some(QualityTarget::MinSsim(ssim))
+        }
+        (None, None) => None,
+    };
+    let metadata_policy = match cli.metadata {
+        Some(policy) => policy,
+        None => config
+            .get_string("metadata")
+            .map_or(Ok(MetadataPolicy::default()), |policy| policy.parse())?,
+    };
+    event!(Level::INFO, "Metadata policy: {:?}", metadata_policy);
+    let chroma_subsampling = match cli.chroma_subsampling {
+        Some(subsampling) => subsampling,
+        None => config
+            .get_string("chroma_subsampling")
+            .map_or(Ok(ChromaSubsampling::default()), |subsampling| {
+                subsampling.parse()
+            })?,
+    };
+    let larger_output = match cli.larger_output {
+        Some(larger_output) => larger_output,
+        None => config
+            .get_string("larger_output")
+            .map_or(Ok(LargerOutput::default()), |larger_output| {
+                larger_output.parse()
+            })?,
+    };
+    event!(Level::INFO, "Larger output policy: {:?}", larger_output);
+    let collision = match cli.collision {
+        Some(collision) => collision,
+        None => config
+            .get_string("collision")
+            .map_or(Ok(Collision::default()), |collision| collision.parse())?,
+    };
+    event!(Level::INFO, "Collision policy: {:?}", collision);
+    let mut outputs = Outputs::new(output_dir, collision);
+    let defaults = Limits::default();
+    let limit_alloc = match cli.limit_alloc {
+        Some(size) => size,
+        None => config
+            .get_string("limit_alloc")
+            .ok()
+            .map(|size| parse_size(&size))
+            .transpose()?
+            .unwrap_or(defaults.max_alloc),
+    };
+    let limits = Limits::default()
+        .with_max_dimensions(
+            cli.limit_width
+                .or_else(|| config.get::<u32>("limit_width").ok())
+                .unwrap_or(defaults.max_width),
+            cli.limit_height
+                .or_else(|| config.get::<u32>("limit_height").ok())
+                .unwrap_or(defaults.max_height),
+        )
+        .with_max_pixels(
+            cli.limit_pixels
+                .or_else(|| config.get::<u64>("limit_pixels").ok())
+                .unwrap_or(defaults.max_pixels),
+        )
+        .with_max_alloc(limit_alloc);
+    event!(Level::INFO, "Limits: {:?}", limits);
+    let options = ShrinkOptions {
+        quality,
+        speed,
+        format,
+        resize,
+        target,
+        metadata_policy,
+        convert_to_srgb: cli.convert_to_srgb || config.get_bool("convert_to_srgb").unwrap_or(false),
+        progressive: cli.progressive || config.get_bool("progressive").unwrap_or(false),
+        chroma_subsampling,
+        lossless: cli.lossless || config.get_bool("lossless").unwrap_or(false),
+        larger_output,
+        limits,
+    };
+    let jobs = match cli.jobs {
+        Some(jobs) => jobs,
+        None => config
+            .get::<usize>("jobs")
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .or_else(|| thread::available_parallelism().ok())
+            .unwrap_or(NonZeroUsize::MIN),
+    };
+    event!(Level::INFO, "Jobs: {}", jobs);
+    const DEFAULT_DOWNLOADS: NonZeroUsize = NonZeroUsize::new(8).unwrap();
+    let downloads = match cli.downloads {
+        Some(downloads) => downloads,
+        None => config
+            .get::<usize>("downloads")
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(DEFAULT_DOWNLOADS),
+    };
+    event!(Level::INFO, "Concurrent downloads: {}", downloads);
+    let patterns = |cli: Vec<String>, key| {
+        if cli.is_empty() {
+            config.get::<Vec<String>>(key).unwrap_or_default()
+        } else {
+            cli
+        }
+    };
+    let walker = Walker::new(
+        &patterns(cli.include, "include"),
+        &patterns(cli.exclude, "exclude"),
+        cli.follow_symlinks || config.get_bool("follow_symlinks").unwrap_or(false),
+        cli.hidden || config.get_bool("hidden").unwrap_or(false),
+    )?;
+
+    let mut statistics = Statistics::default();
+    process_files(
+        &factory,
+        cli.input.into_iter().flat_map(|input| walker.expand(input)),
+        &mut outputs,
+        &options,
+        jobs,
+        downloads,
+        &mut statistics,
+    );
     if cli.stdin {
         event!(
             Level::WARN,
@@ -146,9 +693,12 @@ fn main() -> Result<()> {
         );
         process_files(
             &factory,
-            std::io::stdin().lock().lines().filter_map(Result::ok),
-            output_dir,
-            quality,
+            input_lines(std::io::stdin().lock()).flat_map(|input| walker.expand(input)),
+            &mut outputs,
+            &options,
+            jobs,
+            downloads,
+            &mut statistics,
         );
     }
     if let Some(path) = cli.from_file {
@@ -156,10 +706,149 @@ fn main() -> Result<()> {
         let reader = std::io::BufReader::new(input_file);
         process_files(
             &factory,
-            reader.lines().filter_map(Result::ok),
-            output_dir,
-            quality,
+            input_lines(reader).flat_map(|input| walker.expand(input)),
+            &mut outputs,
+            &options,
+            jobs,
+            downloads,
+            &mut statistics,
         );
     }
+    event!(
+        Level::INFO,
+        "Processed {} images from {} to {} bytes, skipped {}, failed {}",
+        statistics.processed,
+        statistics.input_bytes,
+        statistics.written_bytes,
+        statistics.skipped,
+        statistics.failed
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use img_processor::ImageProcessorError;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    const TEST_IMAGE: &str = "../img-processor/test.jpg";
+
+    /// Factory panicking on inputs named 'panic.jpg' and taking its time over 'slow.jpg',
+    /// counting the inputs started meanwhile
+    #[derive(Default)]
+    struct TestFactory {
+        factory: DefaultImageProcessorFactory,
+        started: AtomicUsize,
+        started_while_slow: AtomicUsize,
+    }
+
+    impl ImageProcessorFactory for TestFactory {
+        fn process_image(
+            &self,
+            image: &Path,
+        ) -> Result<Box<dyn ImageProcessor>, ImageProcessorError> {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            if image.ends_with("panic.jpg") {
+                panic!("Unexpected input");
+            }
+            if image.ends_with("slow.jpg") {
+                thread::sleep(Duration::from_millis(500));
+                let started = self.started.load(Ordering::SeqCst);
+                self.started_while_slow.store(started, Ordering::SeqCst);
+                return self.factory.process_image(Path::new(TEST_IMAGE));
+            }
+            self.factory.process_image(image)
+        }
+
+        fn process_bytes(
+            &self,
+            _image: &[u8],
+        ) -> Result<Box<dyn ImageProcessor>, ImageProcessorError> {
+            Err(ImageProcessorError::UnsupportedFormat)
+        }
+    }
+
+    fn process(inputs: &[&str], directory: &Path, factory: &TestFactory) -> Statistics {
+        std::fs::remove_dir_all(directory).ok();
+        let mut outputs = Outputs::new(directory, Collision::Rename);
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_larger_output(LargerOutput::CopyOriginal);
+        let inputs = inputs.iter().map(|path| {
+            Ok(Input {
+                path: path.to_string(),
+                name: Some(PathBuf::from("test.jpg")),
+            })
+        });
+        let mut statistics = Statistics::default();
+        process_files(
+            factory,
+            inputs,
+            &mut outputs,
+            &options,
+            NonZeroUsize::new(2).unwrap(),
+            NonZeroUsize::MIN,
+            &mut statistics,
+        );
+        statistics
+    }
+
+    #[test]
+    fn test_input_lines() {
+        let lines: Vec<_> = input_lines(&b"a.jpg\n\xff.jpg\nb.jpg\n"[..]).collect();
+        assert_eq!(lines, ["a.jpg", "b.jpg"]);
+    }
+
+    #[test]
+    fn test_panicking_worker() {
+        let directory = Path::new("/tmp/iccli-test-pipeline-panic");
+        let mut inputs = vec!["panic.jpg"];
+        inputs.extend([TEST_IMAGE; 20]);
+        let statistics = process(&inputs, directory, &TestFactory::default());
+        assert_eq!(statistics.failed, 1);
+        assert_eq!(statistics.processed, 20);
+        assert!(directory.join("test-19.jpg").exists());
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn test_slow_input() {
+        let directory = Path::new("/tmp/iccli-test-pipeline-slow");
+        let mut inputs = vec!["slow.jpg"];
+        inputs.extend([TEST_IMAGE; 40]);
+        let factory = TestFactory::default();
+        let statistics = process(&inputs, directory, &factory);
+        assert_eq!(statistics.processed, 41);
+        // The results waiting for the slow input are limited to twice the jobs and downloads
+        assert!(factory.started_while_slow.load(Ordering::SeqCst) <= 1 + 2 * (2 + 1));
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn test_walk_errors() {
+        let directory = Path::new("/tmp/iccli-test-pipeline-walk");
+        std::fs::remove_dir_all(directory).ok();
+        let photos = directory.join("photos");
+        std::fs::create_dir_all(&photos).unwrap();
+        std::fs::copy(TEST_IMAGE, photos.join("photo.jpg")).unwrap();
+        std::os::unix::fs::symlink(directory.join("missing"), photos.join("dangling.jpg")).unwrap();
+        let walker = Walker::new(&[], &[], true, false).unwrap();
+        let mut outputs = Outputs::new(&directory.join("output"), Collision::default());
+        let mut statistics = Statistics::default();
+        process_files(
+            &TestFactory::default(),
+            walker.expand(photos.to_str().unwrap().to_string()),
+            &mut outputs,
+            &ShrinkOptions::new(Quality::try_from(50).unwrap()),
+            NonZeroUsize::MIN,
+            NonZeroUsize::MIN,
+            &mut statistics,
+        );
+        assert_eq!(statistics.failed, 1);
+        assert_eq!(statistics.processed + statistics.skipped, 1);
+        std::fs::remove_dir_all(directory).ok();
+    }
+}
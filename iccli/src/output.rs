@@ -0,0 +1,184 @@
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{Level, event};
+
+/// What to do when an output path is taken by an earlier input of the run or, except for
+/// the default, by an existing file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collision {
+    /// Replace the file
+    Overwrite,
+    /// Leave the file alone and skip the input
+    Skip,
+    /// Fail the input
+    Error,
+    /// Write to the first free path with a numeric suffix, e.g. 'image-1.jpg'
+    Rename,
+    /// Rename outputs sharing a path within the run, but replace files from earlier runs
+    #[default]
+    RenameWithinRun,
+}
+
+impl FromStr for Collision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(Collision::Overwrite),
+            "skip" => Ok(Collision::Skip),
+            "error" => Ok(Collision::Error),
+            "rename" => Ok(Collision::Rename),
+            "rename-within-run" => Ok(Collision::RenameWithinRun),
+            _ => Err(anyhow::anyhow!("Invalid collision policy: {}", s)),
+        }
+    }
+}
+
+/// Output directory and the paths written in it during a run. Paths are claimed in input
+/// order once an output is ready, so the names do not depend on which worker finishes
+/// first and inputs that fail never take a name.
+pub struct Outputs {
+    dir: PathBuf,
+    policy: Collision,
+    claimed: HashSet<PathBuf>,
+}
+
+/// Path with a numeric suffix inserted before the extension
+fn with_suffix(path: &Path, suffix: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!("-{}", suffix));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+impl Outputs {
+    pub fn new(dir: &Path, policy: Collision) -> Self {
+        Outputs {
+            dir: dir.to_path_buf(),
+            policy,
+            claimed: HashSet::new(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Claims the output path according to the policy, returning the path to write to, or
+    /// None when the input is to be skipped
+    pub fn claim(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        let existing = self.policy != Collision::RenameWithinRun;
+        let taken = |path: &Path| self.claimed.contains(path) || (existing && path.exists());
+        let path = match self.policy {
+            _ if !taken(path) => path.to_path_buf(),
+            Collision::Overwrite => {
+                if self.claimed.contains(path) {
+                    event!(
+                        Level::WARN,
+                        "Overwriting the output of an earlier input: {}",
+                        path.display()
+                    );
+                }
+                path.to_path_buf()
+            }
+            Collision::Skip => return Ok(None),
+            Collision::Error => {
+                return Err(anyhow::anyhow!(
+                    "Output file already exists or is written by an earlier input: {}",
+                    path.display()
+                ));
+            }
+            Collision::Rename | Collision::RenameWithinRun => (1..)
+                .map(|suffix| with_suffix(path, suffix))
+                .find(|path| !taken(path))
+                .expect("suffixes are unbounded"),
+        };
+        self.claimed.insert(path.clone());
+        Ok(Some(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_collision_policy() {
+        assert_eq!("skip".parse::<Collision>().unwrap(), Collision::Skip);
+        assert_eq!("Rename".parse::<Collision>().unwrap(), Collision::Rename);
+        assert_eq!(
+            "rename-within-run".parse::<Collision>().unwrap(),
+            Collision::RenameWithinRun
+        );
+        assert!("hash".parse::<Collision>().is_err());
+        assert_eq!(Collision::default(), Collision::RenameWithinRun);
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!(
+            with_suffix(Path::new("out/image.jpg"), 1),
+            Path::new("out/image-1.jpg")
+        );
+        assert_eq!(
+            with_suffix(Path::new("out/image.tar.gz"), 2),
+            Path::new("out/image.tar-2.gz")
+        );
+        assert_eq!(
+            with_suffix(Path::new("out/image"), 3),
+            Path::new("out/image-3")
+        );
+        assert_eq!(with_suffix(Path::new(".image"), 1), Path::new(".image-1"));
+    }
+
+    #[test]
+    fn test_claim() {
+        let directory = Path::new("/tmp/iccli-test-collision");
+        fs::remove_dir_all(directory).ok();
+        fs::create_dir_all(directory).unwrap();
+        let existing = directory.join("existing.jpg");
+        fs::write(&existing, b"earlier run").unwrap();
+        let new = directory.join("new.jpg");
+        let claim = |policy, path: &Path| {
+            let mut outputs = Outputs::new(directory, policy);
+            let first = outputs.claim(path).unwrap();
+            (first, outputs.claim(path))
+        };
+
+        let (first, second) = claim(Collision::Overwrite, &existing);
+        assert_eq!(first.as_deref(), Some(existing.as_path()));
+        assert_eq!(second.unwrap().as_deref(), Some(existing.as_path()));
+
+        let (first, second) = claim(Collision::Skip, &existing);
+        assert_eq!(first, None);
+        assert_eq!(second.unwrap(), None);
+        let (first, second) = claim(Collision::Skip, &new);
+        assert_eq!(first.as_deref(), Some(new.as_path()));
+        assert_eq!(second.unwrap(), None);
+
+        let mut outputs = Outputs::new(directory, Collision::Error);
+        assert!(outputs.claim(&existing).is_err());
+        let (first, second) = claim(Collision::Error, &new);
+        assert_eq!(first.as_deref(), Some(new.as_path()));
+        assert!(second.is_err());
+
+        let (first, second) = claim(Collision::Rename, &existing);
+        assert_eq!(first, Some(directory.join("existing-1.jpg")));
+        assert_eq!(second.unwrap(), Some(directory.join("existing-2.jpg")));
+
+        // Files from earlier runs are replaced, only outputs of the run are renamed
+        let (first, second) = claim(Collision::RenameWithinRun, &existing);
+        assert_eq!(first.as_deref(), Some(existing.as_path()));
+        assert_eq!(second.unwrap(), Some(directory.join("existing-1.jpg")));
+        fs::remove_dir_all(directory).ok();
+    }
+}
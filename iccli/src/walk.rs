@@ -0,0 +1,293 @@
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Component, Path, PathBuf};
+use tracing::{Level, event};
+use walkdir::{DirEntry, WalkDir};
+
+/// Files picked from directories when no include patterns are given
+const DEFAULT_INCLUDE: [&str; 3] = ["*.jpg", "*.jpeg", "*.png"];
+
+/// Input path, with its output name when found in a walked directory
+pub struct Input {
+    pub path: String,
+    pub name: Option<PathBuf>,
+}
+
+/// Path of the output relative to the output directory: the input path when it is relative
+/// and stays below the working directory, its file name otherwise
+pub fn output_name(input: &Path) -> Option<PathBuf> {
+    let below = input
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let name: PathBuf = if below {
+        input
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect()
+    } else {
+        input.file_name()?.into()
+    };
+    name.file_name().is_some().then_some(name)
+}
+
+/// Entry of an input directory that could not be read
+pub struct WalkError {
+    /// Input directory being walked
+    pub input: String,
+    pub error: walkdir::Error,
+}
+
+/// Expands directory inputs into the image files below them
+pub struct Walker {
+    include: GlobSet,
+    exclude: GlobSet,
+    follow_symlinks: bool,
+    hidden: bool,
+}
+
+/// Compiles case-insensitive glob patterns matched against paths relative to the walked directory
+fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(pattern.as_ref())
+                .case_insensitive(true)
+                .build()?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+impl Walker {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        follow_symlinks: bool,
+        hidden: bool,
+    ) -> Result<Self> {
+        let include = if include.is_empty() {
+            glob_set(&DEFAULT_INCLUDE)?
+        } else {
+            glob_set(include)?
+        };
+        Ok(Walker {
+            include,
+            exclude: glob_set(exclude)?,
+            follow_symlinks,
+            hidden,
+        })
+    }
+
+    /// Yields the input itself unless it is a directory, in which case the files below it
+    /// matching the patterns are yielded in a stable, sorted order. Their output names mirror
+    /// the layout below the output name of the directory, as given to files.
+    pub fn expand(&self, input: String) -> Box<dyn Iterator<Item = Result<Input, WalkError>> + '_> {
+        let root = Path::new(&input);
+        if !root.is_dir() {
+            return Box::new(std::iter::once(Ok(Input {
+                path: input,
+                name: None,
+            })));
+        }
+        let prefix = output_name(root).unwrap_or_default();
+        let root = root.to_path_buf();
+        let relative = move |entry: &DirEntry| {
+            entry
+                .path()
+                .strip_prefix(&root)
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        };
+        let entries = WalkDir::new(&input)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry({
+                let relative = relative.clone();
+                // Hidden and excluded directories are skipped with all their contents
+                move |entry| {
+                    entry.depth() == 0
+                        || ((self.hidden || !is_hidden(entry))
+                            && !self.exclude.is_match(relative(entry)))
+                }
+            });
+        Box::new(entries.filter_map(move |entry| match entry {
+            Ok(entry) if entry.file_type().is_file() && self.include.is_match(relative(&entry)) => {
+                let name = Some(prefix.join(relative(&entry)));
+                match entry.into_path().into_os_string().into_string() {
+                    Ok(path) => Some(Ok(Input { path, name })),
+                    Err(path) => {
+                        event!(Level::WARN, "Skipping non UTF-8 path: {:?}", path);
+                        None
+                    }
+                }
+            }
+            Ok(_) => None,
+            Err(error) => Some(Err(WalkError {
+                input: input.clone(),
+                error,
+            })),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Creates the files below a fresh directory
+    fn tree(directory: &Path, files: &[&str]) {
+        fs::remove_dir_all(directory).ok();
+        for file in files {
+            let path = directory.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"image").unwrap();
+        }
+    }
+
+    /// Output names of the files found below the directory
+    fn names(walker: &Walker, directory: &Path) -> Vec<PathBuf> {
+        let input = directory.to_str().unwrap().to_string();
+        walker
+            .expand(input)
+            .map(|input| input.ok().unwrap().name.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_output_name() {
+        let name = |input| output_name(Path::new(input));
+        assert_eq!(name("logo.jpg"), Some("logo.jpg".into()));
+        assert_eq!(name("./a/./logo.jpg"), Some("a/logo.jpg".into()));
+        assert_eq!(name("/photos/a/logo.jpg"), Some("logo.jpg".into()));
+        assert_eq!(name("../a/logo.jpg"), Some("logo.jpg".into()));
+        assert_eq!(name("a"), Some("a".into()));
+        assert_eq!(name("."), None);
+        assert_eq!(name(".."), None);
+    }
+
+    #[test]
+    fn test_mirrored_layout() {
+        let directory = Path::new("/tmp/iccli-test-walk-layout");
+        tree(directory, &["a/logo.jpg", "a/sub/icon.png", "b/logo.jpg"]);
+        let walker = Walker::new(&[], &[], false, false).unwrap();
+        // Directories keep their own name, as files given directly do
+        assert_eq!(
+            names(&walker, &directory.join("a")),
+            [Path::new("a/logo.jpg"), Path::new("a/sub/icon.png")]
+        );
+        assert_eq!(
+            names(&walker, &directory.join("b")),
+            [Path::new("b/logo.jpg")]
+        );
+        assert_eq!(
+            names(&walker, directory),
+            [
+                Path::new("iccli-test-walk-layout/a/logo.jpg"),
+                Path::new("iccli-test-walk-layout/a/sub/icon.png"),
+                Path::new("iccli-test-walk-layout/b/logo.jpg"),
+            ]
+        );
+        // Files are yielded as they are, named by the caller
+        let file = directory.join("a/logo.jpg");
+        let inputs: Vec<_> = walker.expand(file.to_str().unwrap().to_string()).collect();
+        assert_eq!(inputs.len(), 1);
+        assert!(inputs[0].as_ref().ok().unwrap().name.is_none());
+        fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn test_patterns() {
+        let directory = Path::new("/tmp/iccli-test-walk-patterns");
+        tree(
+            directory,
+            &[
+                "photo.jpg",
+                "PHOTO2.JPEG",
+                "notes.txt",
+                ".hidden.jpg",
+                ".cache/cached.jpg",
+                "raw/scan.png",
+                "raw/skip/old.jpg",
+            ],
+        );
+        let walked = |include: &[&str], exclude: &[&str], hidden| {
+            let strings = |patterns: &[&str]| -> Vec<String> {
+                patterns.iter().map(|p| p.to_string()).collect()
+            };
+            let walker = Walker::new(&strings(include), &strings(exclude), false, hidden).unwrap();
+            names(&walker, directory)
+                .into_iter()
+                .map(|name| {
+                    let name = name.strip_prefix("iccli-test-walk-patterns").unwrap();
+                    name.to_str().unwrap().to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        // JPEG and PNG files by default, whatever the case of their extension
+        assert_eq!(
+            walked(&[], &[], false),
+            [
+                "PHOTO2.JPEG",
+                "photo.jpg",
+                "raw/scan.png",
+                "raw/skip/old.jpg"
+            ]
+        );
+        assert_eq!(
+            walked(&[], &[], true),
+            [
+                ".cache/cached.jpg",
+                ".hidden.jpg",
+                "PHOTO2.JPEG",
+                "photo.jpg",
+                "raw/scan.png",
+                "raw/skip/old.jpg"
+            ]
+        );
+        assert_eq!(
+            walked(&["RAW/**"], &[], false),
+            ["raw/scan.png", "raw/skip/old.jpg"]
+        );
+        assert_eq!(walked(&["*.txt"], &[], false), ["notes.txt"]);
+        // Excluded directories are skipped with their contents
+        assert_eq!(
+            walked(&[], &["raw/skip", "photo*"], false),
+            ["raw/scan.png"]
+        );
+        fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let directory = Path::new("/tmp/iccli-test-walk-symlinks");
+        tree(directory, &["photos/photo.jpg", "raw/scan.png"]);
+        std::os::unix::fs::symlink(directory.join("raw"), directory.join("photos/raw")).unwrap();
+        let photos = directory.join("photos");
+        let walker = Walker::new(&[], &[], false, false).unwrap();
+        assert_eq!(names(&walker, &photos), [Path::new("photos/photo.jpg")]);
+        let walker = Walker::new(&[], &[], true, false).unwrap();
+        assert_eq!(
+            names(&walker, &photos),
+            [
+                Path::new("photos/photo.jpg"),
+                Path::new("photos/raw/scan.png")
+            ]
+        );
+
+        // Following a dangling link fails, without stopping the walk
+        std::os::unix::fs::symlink(directory.join("missing"), photos.join("dangling.jpg")).unwrap();
+        let walked: Vec<_> = walker
+            .expand(photos.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(walked.len(), 3);
+        let WalkError { input, .. } = walked[0].as_ref().err().unwrap();
+        assert_eq!(Path::new(input), photos);
+        fs::remove_dir_all(directory).ok();
+    }
+}
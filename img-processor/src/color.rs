@@ -0,0 +1,172 @@
+use crate::{ImageProcessorError, Result};
+use image::{ColorType, DynamicImage, ImageBuffer};
+use moxcms::{ColorProfile, DataColorSpace, Layout, ToneReprCurve, TransformOptions, Xyzd};
+
+/// Largest deviation of the colorants and tone curves of a profile still considered sRGB
+const SRGB_TOLERANCE: f64 = 0.002;
+
+/// Whether the ICC profile describes sRGB, the color space assumed for untagged images
+pub(crate) fn is_srgb(icc_profile: &[u8]) -> bool {
+    let Ok(profile) = ColorProfile::new_from_slice(icc_profile) else {
+        return false;
+    };
+    let srgb = ColorProfile::new_srgb();
+    let same_colorant = |a: &Xyzd, b: &Xyzd| {
+        (a.x - b.x).abs() <= SRGB_TOLERANCE
+            && (a.y - b.y).abs() <= SRGB_TOLERANCE
+            && (a.z - b.z).abs() <= SRGB_TOLERANCE
+    };
+    let same_curve = |a: &Option<ToneReprCurve>, b: &Option<ToneReprCurve>| match (
+        profile.build_8bit_lin_table(a),
+        srgb.build_8bit_lin_table(b),
+    ) {
+        (Ok(a), Ok(b)) => a
+            .iter()
+            .zip(b.iter())
+            .all(|(a, b)| ((a - b).abs() as f64) <= SRGB_TOLERANCE),
+        _ => false,
+    };
+    // Lookup tables take precedence over the colorants, so only matrix/TRC profiles qualify
+    profile.is_matrix_shaper()
+        && profile.lut_a_to_b_perceptual.is_none()
+        && profile.lut_a_to_b_colorimetric.is_none()
+        && profile.lut_a_to_b_saturation.is_none()
+        && same_colorant(&profile.red_colorant, &srgb.red_colorant)
+        && same_colorant(&profile.green_colorant, &srgb.green_colorant)
+        && same_colorant(&profile.blue_colorant, &srgb.blue_colorant)
+        && same_curve(&profile.red_trc, &srgb.red_trc)
+        && same_curve(&profile.green_trc, &srgb.green_trc)
+        && same_curve(&profile.blue_trc, &srgb.blue_trc)
+}
+
+/// Whether the ICC profile describes pixels of the color type, judging by the data color
+/// space in its header. The CMYK profiles of JPEGs the decoder turned into RGB do not.
+pub(crate) fn describes(icc_profile: &[u8], color: ColorType) -> bool {
+    match icc_profile.get(16..20) {
+        Some(b"RGB ") => color.has_color(),
+        Some(b"GRAY") => !color.has_color(),
+        _ => false,
+    }
+}
+
+/// Converts the pixels from the color space described by the ICC profile to sRGB
+pub(crate) fn convert_to_srgb(image: DynamicImage, icc_profile: &[u8]) -> Result<DynamicImage> {
+    let profile = ColorProfile::new_from_slice(icc_profile)
+        .map_err(|e| ImageProcessorError::decode("Failed to parse ICC profile", e))?;
+    let gray = profile.color_space == DataColorSpace::Gray;
+    let alpha = image.color().has_alpha();
+    let (src_layout, dst_layout) = match (gray, alpha) {
+        (true, true) => (Layout::GrayAlpha, Layout::Rgba),
+        (true, false) => (Layout::Gray, Layout::Rgb),
+        (false, true) => (Layout::Rgba, Layout::Rgba),
+        (false, false) => (Layout::Rgb, Layout::Rgb),
+    };
+    let srgb = ColorProfile::new_srgb();
+    let (width, height) = (image.width(), image.height());
+    let conversion_error =
+        |e| ImageProcessorError::decode("Failed to convert ICC profile to sRGB", e);
+    let buffer_error = || ImageProcessorError::malformed("Converted pixels do not fit the image");
+
+    if image.color().bytes_per_pixel() / image.color().channel_count() == 1 {
+        let pixels = match src_layout {
+            Layout::GrayAlpha => image.to_luma_alpha8().into_raw(),
+            Layout::Gray => image.to_luma8().into_raw(),
+            Layout::Rgba => image.to_rgba8().into_raw(),
+            _ => image.to_rgb8().into_raw(),
+        };
+        let mut converted = vec![0; (width * height) as usize * dst_layout.channels()];
+        profile
+            .create_transform_8bit(src_layout, &srgb, dst_layout, TransformOptions::default())
+            .and_then(|transform| transform.transform(&pixels, &mut converted))
+            .map_err(conversion_error)?;
+        let image = match dst_layout {
+            Layout::Rgba => {
+                ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba8)
+            }
+            _ => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb8),
+        };
+        image.ok_or_else(buffer_error)
+    } else {
+        // Deeper images are converted at 16 bits so the extra precision survives
+        let pixels = match src_layout {
+            Layout::GrayAlpha => image.to_luma_alpha16().into_raw(),
+            Layout::Gray => image.to_luma16().into_raw(),
+            Layout::Rgba => image.to_rgba16().into_raw(),
+            _ => image.to_rgb16().into_raw(),
+        };
+        let mut converted = vec![0; (width * height) as usize * dst_layout.channels()];
+        profile
+            .create_transform_16bit(src_layout, &srgb, dst_layout, TransformOptions::default())
+            .and_then(|transform| transform.transform(&pixels, &mut converted))
+            .map_err(conversion_error)?;
+        let image = match dst_layout {
+            Layout::Rgba => {
+                ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba16)
+            }
+            _ => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb16),
+        };
+        image.ok_or_else(buffer_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_p3_to_srgb() {
+        let icc_profile = ColorProfile::new_display_p3().encode().unwrap();
+        // Pure Display P3 green lies outside of sRGB and gets clipped to sRGB green
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, image::Rgb([0, 255, 0])));
+        let converted = convert_to_srgb(image, &icc_profile).unwrap().to_rgb8();
+        let image::Rgb([red, green, blue]) = *converted.get_pixel(0, 0);
+        assert!(red < 10 && green > 245 && blue < 10);
+
+        // Mid gray is the same in both color spaces
+        let image =
+            DynamicImage::ImageRgba16(ImageBuffer::from_pixel(2, 2, image::Rgba([32768; 4])));
+        let converted = convert_to_srgb(image, &icc_profile).unwrap();
+        assert!(matches!(converted, DynamicImage::ImageRgba16(_)));
+        let image::Rgba([red, green, blue, alpha]) = *converted.to_rgba16().get_pixel(1, 1);
+        assert!([red, green, blue].iter().all(|c| c.abs_diff(32768) < 512));
+        assert_eq!(alpha, 32768);
+    }
+
+    #[test]
+    fn test_describes() {
+        let rgb = ColorProfile::new_display_p3().encode().unwrap();
+        let gray = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+        assert!(describes(&rgb, ColorType::Rgb8));
+        assert!(describes(&rgb, ColorType::Rgba16));
+        assert!(!describes(&rgb, ColorType::L8));
+        assert!(describes(&gray, ColorType::La8));
+        assert!(!describes(&gray, ColorType::Rgb8));
+        let mut cmyk = rgb;
+        cmyk[16..20].copy_from_slice(b"CMYK");
+        assert!(!describes(&cmyk, ColorType::Rgb8));
+        assert!(!describes(b"not a profile", ColorType::Rgb8));
+    }
+
+    #[test]
+    fn test_invalid_profile() {
+        let image = DynamicImage::new_rgb8(2, 2);
+        assert!(convert_to_srgb(image, b"not a profile").is_err());
+        assert!(!is_srgb(b"not a profile"));
+    }
+
+    #[test]
+    fn test_is_srgb() {
+        assert!(is_srgb(&ColorProfile::new_srgb().encode().unwrap()));
+        assert!(!is_srgb(&ColorProfile::new_display_p3().encode().unwrap()));
+        assert!(!is_srgb(&ColorProfile::new_adobe_rgb().encode().unwrap()));
+
+        // The sRGB profile of Hewlett-Packard embedded by most cameras and editors
+        let input = std::fs::read("test.jpg").unwrap();
+        let mut decoder =
+            image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(input)).unwrap();
+        let icc_profile = image::ImageDecoder::icc_profile(&mut decoder)
+            .unwrap()
+            .unwrap();
+        assert!(is_srgb(&icc_profile));
+    }
+}
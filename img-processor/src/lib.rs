@@ -1,26 +1,169 @@
-#![allow(unused)]
-
 use image::{
-    ImageDecoder,
-    codecs::jpeg::{JpegDecoder, JpegEncoder},
+    DynamicImage, GenericImageView, ImageDecoder, ImageEncoder,
+    codecs::{
+        avif::AvifEncoder,
+        jpeg::JpegDecoder,
+        png::{CompressionType, FilterType, PngDecoder, PngEncoder},
+    },
+    metadata::Orientation,
 };
+use metadata::Metadata;
 use std::{
-    fs::File,
-    io::BufReader,
+    borrow::Cow,
+    fmt,
+    fs::{self, File},
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
+mod color;
+mod limits;
+mod lossless;
+mod metadata;
+mod policy;
+mod registry;
+mod resize;
+mod ssim;
+
+pub use image::{ColorType, ImageFormat};
+pub use limits::Limits;
+pub use policy::MetadataPolicy;
+pub use registry::{ProcessorRegistry, Registration};
+pub use resize::{Resize, ResizeFilter, ResizeMode};
+
+/// Underlying error of a decoding or encoding failure
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ImageProcessorError {
     #[error("Unsupported image format")]
     UnsupportedFormat,
+    #[error("Image content is {detected:?} but its file extension indicates {extension:?}")]
+    FormatMismatch {
+        detected: ImageFormat,
+        extension: ImageFormat,
+    },
     #[error("Quality value out of range")]
     QualityOutOfRange,
-    #[error("Image I/O error")]
-    IoError(#[from] std::io::Error),
-    #[error("Image decoding error")]
-    DecodingError(String),
+    #[error("Speed value out of range")]
+    SpeedOutOfRange,
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("No quality produces an output of at most {0} bytes")]
+    TargetSizeUnreachable(u64),
+    #[error("No quality produces an output with a structural similarity of at least {0}")]
+    TargetSsimUnreachable(f64),
+    /// The input could not be read
+    #[error("Failed to read input{}", display_path(path))]
+    ReadInput {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    /// The output could not be written
+    #[error("Failed to write output{}", display_path(path))]
+    WriteOutput {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    /// The input is malformed or uses features the decoders do not support
+    #[error("Failed to decode input{}: {message}", display_path(path))]
+    Decode {
+        path: Option<PathBuf>,
+        message: String,
+        source: Option<Source>,
+    },
+    /// The encoder rejected the image
+    #[error("Failed to encode {format:?} output{}: {message}", display_path(path))]
+    Encode {
+        path: Option<PathBuf>,
+        format: OutputFormat,
+        message: String,
+        source: Option<Source>,
+    },
+    /// The input exceeds the resource limits of decoding
+    #[error("Input{} exceeds the limits: {message}", display_path(path))]
+    LimitExceeded {
+        path: Option<PathBuf>,
+        message: String,
+    },
+}
+
+/// Formats the optional path of an error as a suffix of its message
+fn display_path(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!(" {}", path.display()),
+        None => String::new(),
+    }
+}
+
+impl ImageProcessorError {
+    /// Failure to decode the input, caused by the given error
+    pub(crate) fn decode(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        ImageProcessorError::Decode {
+            path: None,
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// Malformed input detected without an underlying error
+    pub(crate) fn malformed(message: impl Into<String>) -> Self {
+        ImageProcessorError::Decode {
+            path: None,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Failure to decode the input reported by the image crate, which also checks the limits
+    pub(crate) fn from_image(message: impl Into<String>, error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Limits(limits) => ImageProcessorError::LimitExceeded {
+                path: None,
+                message: limits.to_string(),
+            },
+            error => ImageProcessorError::decode(message, error),
+        }
+    }
+
+    /// Failure to encode the output in the given format
+    pub(crate) fn encode(
+        format: OutputFormat,
+        message: impl Into<String>,
+        source: Option<Source>,
+    ) -> Self {
+        ImageProcessorError::Encode {
+            path: None,
+            format,
+            message: message.into(),
+            source,
+        }
+    }
+
+    /// Adds the input path to errors about the input lacking one
+    pub(crate) fn with_input_path(mut self, input_path: &Path) -> Self {
+        if let ImageProcessorError::ReadInput { path, .. }
+        | ImageProcessorError::Decode { path, .. }
+        | ImageProcessorError::LimitExceeded { path, .. } = &mut self
+        {
+            path.get_or_insert_with(|| input_path.to_path_buf());
+        }
+        self
+    }
+
+    /// Adds the output path to errors about the output lacking one
+    pub(crate) fn with_output_path(mut self, output_path: &Path) -> Self {
+        if let ImageProcessorError::WriteOutput { path, .. }
+        | ImageProcessorError::Encode { path, .. } = &mut self
+        {
+            path.get_or_insert_with(|| output_path.to_path_buf());
+        }
+        self
+    }
 }
 
 type Result<T> = std::result::Result<T, ImageProcessorError>;
@@ -29,12 +172,40 @@ type Result<T> = std::result::Result<T, ImageProcessorError>;
 pub trait ImageProcessorFactory {
     /// Starts processing an image at the given path
     fn process_image(&self, image: &Path) -> Result<Box<dyn ImageProcessor>>;
+
+    /// Starts processing an image held in memory
+    fn process_bytes(&self, image: &[u8]) -> Result<Box<dyn ImageProcessor>>;
+
+    /// Starts processing an image read to the end from the given reader
+    fn process_reader(&self, mut reader: impl Read) -> Result<Box<dyn ImageProcessor>>
+    where
+        Self: Sized,
+    {
+        let mut image = Vec::new();
+        reader
+            .read_to_end(&mut image)
+            .map_err(|source| ImageProcessorError::ReadInput { path: None, source })?;
+        self.process_bytes(&image)
+    }
 }
 
 /// Represents the quality of the image compression, ranging from 0 to 100
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Quality(u8);
 
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Quality {
+    /// Quality from 0 to 100
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
 impl TryFrom<u64> for Quality {
     type Error = ImageProcessorError;
 
@@ -47,57 +218,1040 @@ impl TryFrom<u64> for Quality {
     }
 }
 
-/// Default implementation of the ImageProcessorFactory
-pub struct DefaultImageProcessorFactory {}
+/// Represents the encoding speed of formats trading effort for size, ranging from
+/// 1 (slowest, smallest output) to 10 (fastest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Speed(u8);
 
-impl ImageProcessorFactory for DefaultImageProcessorFactory {
-    fn process_image(&self, image: &Path) -> Result<Box<dyn ImageProcessor>> {
-        if let Some(extension) = image.extension().and_then(|s| s.to_str())
-            && (extension == "jpg" || extension == "jpeg")
-        {
-            Ok(Box::new(JpegProcessor {
-                input_path: image.to_path_buf(),
-            }))
+impl Default for Speed {
+    fn default() -> Self {
+        Speed(4)
+    }
+}
+
+impl Speed {
+    /// Speed from 1 to 10
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u64> for Speed {
+    type Error = ImageProcessorError;
+
+    fn try_from(value: u64) -> Result<Self> {
+        if (1..=10).contains(&value) {
+            Ok(Speed(value as u8))
         } else {
-            Err(ImageProcessorError::UnsupportedFormat)
+            Err(ImageProcessorError::SpeedOutOfRange)
+        }
+    }
+}
+
+/// Resolution of the color channels of JPEG output relative to the brightness channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, full color resolution, keeps colored text and edges crisp
+    Ratio444,
+    /// 4:2:2, half the horizontal color resolution
+    Ratio422,
+    /// 4:2:0, half the horizontal and vertical color resolution, smallest output
+    #[default]
+    Ratio420,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = ImageProcessorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "4:4:4" | "444" => Ok(ChromaSubsampling::Ratio444),
+            "4:2:2" | "422" => Ok(ChromaSubsampling::Ratio422),
+            "4:2:0" | "420" => Ok(ChromaSubsampling::Ratio420),
+            _ => Err(ImageProcessorError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+impl From<ChromaSubsampling> for jpeg_encoder::SamplingFactor {
+    fn from(subsampling: ChromaSubsampling) -> Self {
+        match subsampling {
+            ChromaSubsampling::Ratio444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Ratio422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Ratio420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Image formats the processors can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Lossy JPEG
+    Jpeg,
+    /// Lossless PNG, optimized for size
+    Png,
+    /// Lossy WebP, with the quality mapped onto the WebP quality scale
+    WebP,
+    /// Lossless WebP
+    WebPLossless,
+    /// Lossy AVIF, encoded on the CPU with the configured speed
+    Avif,
+}
+
+/// What happens when the output would not be smaller than the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LargerOutput {
+    /// Writes the output anyway
+    Keep,
+    /// Copies the original through unchanged when it satisfies the options, otherwise
+    /// writes nothing like `Skip`
+    #[default]
+    CopyOriginal,
+    /// Writes nothing and reports the output as not smaller
+    Skip,
+}
+
+impl FromStr for LargerOutput {
+    type Err = ImageProcessorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(LargerOutput::Keep),
+            "copy" | "copy-original" => Ok(LargerOutput::CopyOriginal),
+            "skip" => Ok(LargerOutput::Skip),
+            _ => Err(ImageProcessorError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// File extension conventionally used for the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP | OutputFormat::WebPLossless => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    /// Whether the format preserves the pixels exactly, ignoring the quality
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::WebPLossless)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ImageProcessorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::WebP),
+            "webp-lossless" => Ok(OutputFormat::WebPLossless),
+            "avif" => Ok(OutputFormat::Avif),
+            _ => Err(ImageProcessorError::UnsupportedFormat),
+        }
+    }
+}
+
+/// Goal of the quality search replacing the fixed quality
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityTarget {
+    /// Highest quality producing an output of at most the given number of bytes
+    MaxSize(u64),
+    /// Lowest quality producing an output with at least the given structural similarity
+    /// (SSIM, 0 to 1) to the decoded input. The output has to be decoded again, which AVIF
+    /// does not support.
+    MinSsim(f64),
+}
+
+/// Options controlling how an image is shrunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShrinkOptions {
+    /// Quality of lossy encodings
+    pub quality: Quality,
+    /// Encoding speed of formats supporting an effort trade-off (AVIF)
+    pub speed: Speed,
+    /// Format to transcode to, `None` keeps the input format
+    pub format: Option<OutputFormat>,
+    /// Maximum dimensions to resize to, `None` keeps the input dimensions
+    pub resize: Option<Resize>,
+    /// Searches for the quality meeting the target, `None` uses the fixed quality
+    pub target: Option<QualityTarget>,
+    /// Which EXIF, XMP and IPTC metadata of the input is carried over into the output
+    pub metadata_policy: MetadataPolicy,
+    /// Converts the pixels to sRGB and drops the ICC profile instead of embedding it
+    pub convert_to_srgb: bool,
+    /// Encodes JPEG output progressively, refining the whole image scan by scan
+    pub progressive: bool,
+    /// Resolution of the color channels of JPEG output
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Optimizes JPEG input without re-encoding, keeping the DCT coefficients and only
+    /// rewriting the Huffman tables, scans and metadata
+    pub lossless: bool,
+    /// What happens when the output would not be smaller than the input
+    pub larger_output: LargerOutput,
+    /// Resource limits rejecting inputs before they are decoded
+    pub limits: Limits,
+}
+
+impl ShrinkOptions {
+    /// Creates options keeping the input format and using the given quality
+    pub fn new(quality: Quality) -> Self {
+        ShrinkOptions {
+            quality,
+            speed: Speed::default(),
+            format: None,
+            resize: None,
+            target: None,
+            metadata_policy: MetadataPolicy::default(),
+            convert_to_srgb: false,
+            progressive: false,
+            chroma_subsampling: ChromaSubsampling::default(),
+            lossless: false,
+            larger_output: LargerOutput::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Sets the encoding speed
+    pub fn with_speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Transcodes the output to the given format
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Resizes the output to the given maximum dimensions
+    pub fn with_resize(mut self, resize: Resize) -> Self {
+        self.resize = Some(resize);
+        self
+    }
+
+    /// Searches for the quality meeting the target instead of using the fixed quality
+    pub fn with_target(mut self, target: QualityTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Sets which metadata of the input is carried over into the output
+    pub fn with_metadata_policy(mut self, metadata_policy: MetadataPolicy) -> Self {
+        self.metadata_policy = metadata_policy;
+        self
+    }
+
+    /// Sets whether the pixels are converted to sRGB instead of embedding the ICC profile
+    pub fn with_convert_to_srgb(mut self, convert_to_srgb: bool) -> Self {
+        self.convert_to_srgb = convert_to_srgb;
+        self
+    }
+
+    /// Sets whether JPEG output is encoded progressively
+    pub fn with_progressive(mut self, progressive: bool) -> Self {
+        self.progressive = progressive;
+        self
+    }
+
+    /// Sets the resolution of the color channels of JPEG output
+    pub fn with_chroma_subsampling(mut self, chroma_subsampling: ChromaSubsampling) -> Self {
+        self.chroma_subsampling = chroma_subsampling;
+        self
+    }
+
+    /// Sets whether JPEG input is optimized without re-encoding. The pixels are not rotated
+    /// in this mode, so the EXIF orientation tag is kept for viewers to apply.
+    pub fn with_lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+
+    /// Sets what happens when the output would not be smaller than the input
+    pub fn with_larger_output(mut self, larger_output: LargerOutput) -> Self {
+        self.larger_output = larger_output;
+        self
+    }
+
+    /// Sets the resource limits rejecting inputs before they are decoded
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Drops the metadata the options exclude from the output
+    fn filter_metadata(&self, metadata: Metadata) -> Metadata {
+        self.metadata_policy.apply(metadata)
+    }
+
+    /// Converts the pixels to sRGB and drops the ICC profile when requested, or when
+    /// the output cannot embed it. Profiles of another color model than the decoded pixels
+    /// cannot be applied to them and are dropped.
+    fn convert_color(
+        &self,
+        image: DynamicImage,
+        format: OutputFormat,
+        metadata: &mut Metadata,
+    ) -> Result<DynamicImage> {
+        metadata
+            .icc_profile
+            .take_if(|icc_profile| !color::describes(icc_profile, image.color()));
+        // The AVIF encoder cannot embed ICC profiles, and WebP stores gray images as RGB
+        let webp = matches!(format, OutputFormat::WebP | OutputFormat::WebPLossless);
+        let convert = self.convert_to_srgb
+            || format == OutputFormat::Avif
+            || (webp && !image.color().has_color());
+        match metadata.icc_profile.take_if(|_| convert) {
+            Some(icc_profile) => color::convert_to_srgb(image, &icc_profile),
+            None => Ok(image),
+        }
+    }
+
+    /// Whether the original still satisfies the options after processing the image, holding
+    /// the same pixels and all of its metadata
+    fn keeps_original(
+        &self,
+        image: &DynamicImage,
+        dimensions: (u32, u32),
+        metadata: &Metadata,
+        had_icc_profile: bool,
+    ) -> bool {
+        image.dimensions() == dimensions
+            && metadata.orientation == Orientation::NoTransforms
+            && metadata.icc_profile.is_some() == had_icc_profile
+            && self.metadata_policy == MetadataPolicy::KeepAll
+    }
+
+    /// Whether JPEG output may be the baseline 4:2:0 encoding of an unknown original, which
+    /// cannot stand in for progressive or otherwise subsampled output asked for explicitly
+    fn default_jpeg_encoding(&self) -> bool {
+        !self.progressive && self.chroma_subsampling == ChromaSubsampling::default()
+    }
+
+    /// Writes the encoded image, or when it is not smaller than the original, whatever the
+    /// larger output policy asks for. The original is only copied when `keeps_original`.
+    fn write_smallest(
+        &self,
+        output: &mut dyn Write,
+        original: &[u8],
+        encoded: &[u8],
+        keeps_original: bool,
+    ) -> Result<ShrinkOutcome> {
+        let (written, outcome) =
+            if encoded.len() < original.len() || self.larger_output == LargerOutput::Keep {
+                (encoded, ShrinkOutcome::Written)
+            } else if keeps_original && self.larger_output == LargerOutput::CopyOriginal {
+                (original, ShrinkOutcome::CopiedOriginal)
+            } else {
+                return Ok(ShrinkOutcome::NotSmaller);
+            };
+        output
+            .write_all(written)
+            .map_err(|source| ImageProcessorError::WriteOutput { path: None, source })?;
+        Ok(outcome)
+    }
+
+    /// Applies the resize step between decoding and encoding
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        match &self.resize {
+            Some(resize) => resize.apply(image),
+            None => image,
+        }
+    }
+}
+
+impl From<Quality> for ShrinkOptions {
+    fn from(quality: Quality) -> Self {
+        ShrinkOptions::new(quality)
+    }
+}
+
+/// Default implementation of the ImageProcessorFactory, a registry of the built-in
+/// processors further processors can be registered with
+pub type DefaultImageProcessorFactory = ProcessorRegistry;
+
+/// Number of leading bytes inspected when sniffing the image format
+const SNIFF_LEN: u64 = 32;
+
+/// Reads the leading bytes of the file, none when it cannot be read
+fn sniff(image: &Path) -> Vec<u8> {
+    let mut header = Vec::new();
+    File::open(image)
+        .and_then(|file| file.take(SNIFF_LEN).read_to_end(&mut header))
+        .ok();
+    header
+}
+
+/// What was written for a shrunk image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShrinkOutcome {
+    /// The processed image was written
+    Written,
+    /// The processed image was not smaller, so the original was copied through unchanged
+    CopiedOriginal,
+    /// The processed image was not smaller than the input and nothing was written
+    NotSmaller,
+}
+
+/// Outcome of shrinking an image
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ShrinkReport {
+    /// Size of the input in bytes
+    pub input_size: u64,
+    /// Size of the processed image in bytes, whether or not it was written
+    pub output_size: u64,
+    /// Width and height of the input in pixels
+    pub input_dimensions: (u32, u32),
+    /// Width and height of the processed image in pixels, after orientation and resizing
+    pub output_dimensions: (u32, u32),
+    /// Color type of the decoded input
+    pub color_type: ColorType,
+    /// Format the processed image was encoded in
+    pub format: OutputFormat,
+    /// Quality used for the output, the searched one when a quality target is set. Lossless
+    /// JPEG optimization reports the configured one, which it does not use.
+    pub quality: Quality,
+    /// Structural similarity of the output to the decoded input, measured when
+    /// searching for a minimum similarity
+    pub ssim: Option<f64>,
+    /// Time spent decoding the input
+    pub decode_time: Duration,
+    /// Time spent processing and encoding the image, including the quality search
+    pub encode_time: Duration,
+    /// Whether the processed image, the original or nothing was written
+    pub outcome: ShrinkOutcome,
+}
+
+impl ShrinkReport {
+    /// Report of an image written in full at its input dimensions, without a measured
+    /// similarity nor timings, which the `with_` methods add
+    pub fn new(
+        input_size: u64,
+        output_size: u64,
+        dimensions: (u32, u32),
+        color_type: ColorType,
+        format: OutputFormat,
+        quality: Quality,
+    ) -> Self {
+        ShrinkReport {
+            input_size,
+            output_size,
+            input_dimensions: dimensions,
+            output_dimensions: dimensions,
+            color_type,
+            format,
+            quality,
+            ssim: None,
+            decode_time: Duration::ZERO,
+            encode_time: Duration::ZERO,
+            outcome: ShrinkOutcome::Written,
+        }
+    }
+
+    /// Sets the dimensions of the processed image
+    pub fn with_output_dimensions(mut self, width: u32, height: u32) -> Self {
+        self.output_dimensions = (width, height);
+        self
+    }
+
+    /// Sets the measured structural similarity of the output to the input
+    pub fn with_ssim(mut self, ssim: f64) -> Self {
+        self.ssim = Some(ssim);
+        self
+    }
+
+    /// Sets the time spent decoding and encoding
+    pub fn with_times(mut self, decode_time: Duration, encode_time: Duration) -> Self {
+        self.decode_time = decode_time;
+        self.encode_time = encode_time;
+        self
+    }
+
+    /// Sets what was written
+    pub fn with_outcome(mut self, outcome: ShrinkOutcome) -> Self {
+        self.outcome = outcome;
+        self
+    }
+
+    /// Number of bytes written to the output
+    pub fn written_size(&self) -> u64 {
+        match self.outcome {
+            ShrinkOutcome::Written => self.output_size,
+            ShrinkOutcome::CopiedOriginal => self.input_size,
+            ShrinkOutcome::NotSmaller => 0,
         }
     }
 }
 
 /// Trait for image processors
 pub trait ImageProcessor {
-    /// Shrink the image to the specified output path with the given quality
-    fn shrink_to(&self, output_path: &Path, quality: Quality) -> Result<()>;
+    /// Shrink the image into the given writer with the given options
+    fn shrink_into(&self, output: &mut dyn Write, options: &ShrinkOptions) -> Result<ShrinkReport>;
+
+    /// Shrink the image to the specified output path with the given options. The output
+    /// is written into a temporary file in the same directory which only replaces the
+    /// destination once complete, so failures never leave a truncated file behind.
+    fn shrink_to(&self, output_path: &Path, options: &ShrinkOptions) -> Result<ShrinkReport> {
+        let (report, output) = self.shrink_pending(output_path, options)?;
+        if let Some(output) = output {
+            output.persist(output_path)?;
+        }
+        Ok(report)
+    }
+
+    /// Shrink the image into a temporary file next to the output path without replacing
+    /// anything yet, so that the caller can still choose the final name in the same
+    /// directory. No file is returned when the output is not to be written.
+    fn shrink_pending(
+        &self,
+        output_path: &Path,
+        options: &ShrinkOptions,
+    ) -> Result<(ShrinkReport, Option<PendingOutput>)> {
+        let mut file = temporary_file_for(output_path)?;
+        // The temporary file is removed when dropped without being persisted
+        let report = self
+            .shrink_into(&mut file, options)
+            .map_err(|e| e.with_output_path(output_path))?;
+        let output =
+            (report.outcome != ShrinkOutcome::NotSmaller).then_some(PendingOutput { file });
+        Ok((report, output))
+    }
+}
+
+/// Complete output held in a temporary file, removed unless persisted
+pub struct PendingOutput {
+    file: tempfile::NamedTempFile,
+}
+
+impl PendingOutput {
+    /// Moves the output to its final path, which must be in the directory the output was
+    /// shrunk for, replacing any file there
+    pub fn persist(self, output_path: &Path) -> Result<()> {
+        let write_error = |source| ImageProcessorError::WriteOutput {
+            path: Some(output_path.to_path_buf()),
+            source,
+        };
+        self.file.as_file().sync_all().map_err(write_error)?;
+        self.file
+            .persist(output_path)
+            .map_err(|e| write_error(e.error))?;
+        Ok(())
+    }
+}
+
+/// Creates a hidden temporary file next to the output path, with the permissions of the
+/// file it replaces or those of a newly created file
+fn temporary_file_for(output_path: &Path) -> Result<tempfile::NamedTempFile> {
+    let directory = output_path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".").suffix(".tmp");
+    match fs::metadata(output_path) {
+        Ok(metadata) => {
+            builder.permissions(metadata.permissions());
+        }
+        #[cfg(unix)]
+        Err(_) => {
+            use std::os::unix::fs::PermissionsExt;
+            // Temporary files are private by default, outputs are subject to the umask only
+            builder.permissions(fs::Permissions::from_mode(0o666));
+        }
+        #[cfg(not(unix))]
+        Err(_) => {}
+    }
+    builder
+        .tempfile_in(directory)
+        .map_err(|source| ImageProcessorError::WriteOutput {
+            path: Some(output_path.to_path_buf()),
+            source,
+        })
+}
+
+/// Where a processor reads its input image from
+pub enum ImageSource {
+    /// Image file, read when the image is shrunk
+    File(PathBuf),
+    /// Image held in memory
+    Memory(Vec<u8>),
+}
+
+impl ImageSource {
+    /// Reads the whole image
+    pub fn read(&self) -> Result<Cow<'_, [u8]>> {
+        match self {
+            ImageSource::File(path) => {
+                fs::read(path)
+                    .map(Cow::Owned)
+                    .map_err(|source| ImageProcessorError::ReadInput {
+                        path: Some(path.clone()),
+                        source,
+                    })
+            }
+            ImageSource::Memory(bytes) => Ok(Cow::Borrowed(bytes)),
+        }
+    }
+
+    /// Adds the path of file sources to errors about the input
+    fn context(&self, error: ImageProcessorError) -> ImageProcessorError {
+        match self {
+            ImageSource::File(path) => error.with_input_path(path),
+            ImageSource::Memory(_) => error,
+        }
+    }
 }
 
 struct JpegProcessor {
-    input_path: PathBuf,
+    source: ImageSource,
 }
 
 impl ImageProcessor for JpegProcessor {
-    fn shrink_to(&self, output_path: &Path, quality: Quality) -> Result<()> {
-        let file_stream =
-            BufReader::new(File::open(&self.input_path).map_err(ImageProcessorError::IoError)?);
-        let decoder = JpegDecoder::new(file_stream).map_err(|e| {
-            ImageProcessorError::DecodingError(format!("Failed to start decoding JPEG: {}", e))
-        })?;
-        let mut buffer = vec![0; decoder.total_bytes() as usize];
-        let (width, height) = decoder.dimensions();
-        let color_type = decoder.original_color_type();
-        decoder.read_image(&mut buffer).map_err(|e| {
-            ImageProcessorError::DecodingError(format!("Failed to parse JPEG image: {}", e))
-        })?;
-        let mut encoder = JpegEncoder::new_with_quality(
-            File::create(output_path).map_err(ImageProcessorError::IoError)?,
-            quality.0,
-        );
+    fn shrink_into(&self, output: &mut dyn Write, options: &ShrinkOptions) -> Result<ShrinkReport> {
+        self.shrink(output, options)
+            .map_err(|e| self.source.context(e))
+    }
+}
+
+impl JpegProcessor {
+    fn shrink(&self, output: &mut dyn Write, options: &ShrinkOptions) -> Result<ShrinkReport> {
+        let input = self.source.read()?;
+        let started = Instant::now();
+        let mut decoder = JpegDecoder::new(Cursor::new(&input))
+            .map_err(|e| ImageProcessorError::from_image("Failed to start decoding JPEG", e))?;
+        options.limits.apply(&mut decoder)?;
+        let color_type = decoder.color_type();
+        if options.lossless {
+            // Only the headers are decoded, the coefficients are transcoded directly
+            let dimensions = decoder.dimensions();
+            let decode_time = started.elapsed();
+            return shrink_jpeg_losslessly(&input, output, options).map(|(optimized, outcome)| {
+                ShrinkReport {
+                    input_size: input.len() as u64,
+                    output_size: optimized as u64,
+                    input_dimensions: dimensions,
+                    output_dimensions: dimensions,
+                    color_type,
+                    format: OutputFormat::Jpeg,
+                    quality: options.quality,
+                    ssim: None,
+                    decode_time,
+                    encode_time: started.elapsed() - decode_time,
+                    outcome,
+                }
+            });
+        }
+        let mut metadata = Metadata::read(&mut decoder)?;
+        // The decoder only exposes the main XMP packet
+        metadata.xmp = metadata.xmp.map(|xmp| {
+            metadata::merge_extended_xmp(xmp, lossless::app1_payloads(&input).into_iter())
+        });
+        let mut metadata = options.filter_metadata(metadata);
+        let image = DynamicImage::from_decoder(decoder)
+            .map_err(|e| ImageProcessorError::from_image("Failed to parse JPEG image", e))?;
+        let decode_time = started.elapsed();
+        let dimensions = image.dimensions();
+        let had_icc_profile = metadata.icc_profile.is_some();
+        let format = options.format.unwrap_or(OutputFormat::Jpeg);
+        let mut image = options.convert_color(image, format, &mut metadata)?;
+        image.apply_orientation(metadata.orientation);
+        let image = options.resize(image);
+        let keeps_original = format == OutputFormat::Jpeg
+            && options.default_jpeg_encoding()
+            && options.keeps_original(&image, dimensions, &metadata, had_icc_profile);
+        let (encoded, quality, ssim) = encode_with_target(&image, format, options, &metadata)?;
+        let encode_time = started.elapsed() - decode_time;
+        let outcome = options.write_smallest(output, &input, &encoded, keeps_original)?;
+        Ok(ShrinkReport {
+            input_size: input.len() as u64,
+            output_size: encoded.len() as u64,
+            input_dimensions: dimensions,
+            output_dimensions: image.dimensions(),
+            color_type,
+            format,
+            quality,
+            ssim,
+            decode_time,
+            encode_time,
+            outcome,
+        })
+    }
+}
+
+/// Optimizes a JPEG file without touching its pixels, which rules out every option
+/// changing them. Returns the size of the optimized file and what was written.
+fn shrink_jpeg_losslessly(
+    input: &[u8],
+    output: &mut dyn Write,
+    options: &ShrinkOptions,
+) -> Result<(usize, ShrinkOutcome)> {
+    let conflict = if options
+        .format
+        .is_some_and(|format| format != OutputFormat::Jpeg)
+    {
+        Some("format")
+    } else if options.resize.is_some() {
+        Some("resize")
+    } else if options.target.is_some() {
+        Some("target")
+    } else if options.convert_to_srgb {
+        Some("convert_to_srgb")
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        return Err(ImageProcessorError::InvalidValue(format!(
+            "{} cannot be combined with lossless optimization",
+            conflict
+        )));
+    }
+    let optimized = lossless::optimize_jpeg(
+        input,
+        options.progressive,
+        &options.metadata_policy,
+        &options.limits,
+    )?;
+    // The coefficients and so the subsampling are kept, only the scans may change
+    let keeps_original = options.metadata_policy == MetadataPolicy::KeepAll && !options.progressive;
+    let outcome = options.write_smallest(output, input, &optimized, keeps_original)?;
+    Ok((optimized.len(), outcome))
+}
+
+/// Lossless PNG optimizer: re-encodes the pixels with the smallest filter and
+/// compression combination, falling back to the original file when no candidate
+/// beats it and the larger output policy allows. The quality setting only applies
+/// when transcoding to a lossy format.
+struct PngProcessor {
+    source: ImageSource,
+}
+
+impl ImageProcessor for PngProcessor {
+    fn shrink_into(&self, output: &mut dyn Write, options: &ShrinkOptions) -> Result<ShrinkReport> {
+        self.shrink(output, options)
+            .map_err(|e| self.source.context(e))
+    }
+}
+
+impl PngProcessor {
+    fn shrink(&self, output: &mut dyn Write, options: &ShrinkOptions) -> Result<ShrinkReport> {
+        let original = self.source.read()?;
+        let started = Instant::now();
+        let mut decoder = PngDecoder::new(Cursor::new(&original))
+            .map_err(|e| ImageProcessorError::from_image("Failed to start decoding PNG", e))?;
+        options.limits.apply(&mut decoder)?;
+        let color_type = decoder.color_type();
+        let mut metadata = options.filter_metadata(Metadata::read(&mut decoder)?);
+        let image = DynamicImage::from_decoder(decoder)
+            .map_err(|e| ImageProcessorError::from_image("Failed to parse PNG image", e))?;
+        let decode_time = started.elapsed();
+        let dimensions = image.dimensions();
+        let had_icc_profile = metadata.icc_profile.is_some();
+        let format = options.format.unwrap_or(OutputFormat::Png);
+        let mut image = options.convert_color(image, format, &mut metadata)?;
+        image.apply_orientation(metadata.orientation);
+        let image = options.resize(image);
+        let keeps_original = format == OutputFormat::Png
+            && options.keeps_original(&image, dimensions, &metadata, had_icc_profile);
+        let (encoded, quality, ssim) = encode_with_target(&image, format, options, &metadata)?;
+        let encode_time = started.elapsed() - decode_time;
+        let outcome = options.write_smallest(output, &original, &encoded, keeps_original)?;
+        Ok(ShrinkReport {
+            input_size: original.len() as u64,
+            output_size: encoded.len() as u64,
+            input_dimensions: dimensions,
+            output_dimensions: image.dimensions(),
+            color_type,
+            format,
+            quality,
+            ssim,
+            decode_time,
+            encode_time,
+            outcome,
+        })
+    }
+}
+
+/// Encodes the image with the fixed quality, or with the quality found by searching
+/// for the quality target, returning the encoding, the quality used and the measured
+/// structural similarity
+fn encode_with_target(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &ShrinkOptions,
+    metadata: &Metadata,
+) -> Result<(Vec<u8>, Quality, Option<f64>)> {
+    match options.target {
+        None => Ok((
+            encode(image, format, options, metadata)?,
+            options.quality,
+            None,
+        )),
+        Some(QualityTarget::MaxSize(max_size)) if format.is_lossless() => {
+            let encoded = encode(image, format, options, metadata)?;
+            if encoded.len() as u64 > max_size {
+                return Err(ImageProcessorError::TargetSizeUnreachable(max_size));
+            }
+            Ok((encoded, options.quality, None))
+        }
+        Some(QualityTarget::MaxSize(max_size)) => {
+            // Bisect for the highest quality fitting, the size grows with the quality
+            let mut best = None;
+            let (mut low, mut high) = (0, 100);
+            while low <= high {
+                let quality = Quality(low + (high - low) / 2);
+                let candidate = ShrinkOptions {
+                    quality,
+                    ..options.clone()
+                };
+                let encoded = encode(image, format, &candidate, metadata)?;
+                if encoded.len() as u64 <= max_size {
+                    best = Some((encoded, quality, None));
+                    low = quality.0 + 1;
+                } else if quality.0 == 0 {
+                    break;
+                } else {
+                    high = quality.0 - 1;
+                }
+            }
+            best.ok_or(ImageProcessorError::TargetSizeUnreachable(max_size))
+        }
+        Some(QualityTarget::MinSsim(_)) if format.is_lossless() => Ok((
+            encode(image, format, options, metadata)?,
+            options.quality,
+            Some(1.0),
+        )),
+        Some(QualityTarget::MinSsim(_)) if format == OutputFormat::Avif => {
+            Err(ImageProcessorError::UnsupportedFormat)
+        }
+        Some(QualityTarget::MinSsim(min_ssim)) => {
+            // Bisect for the lowest quality similar enough, the similarity grows with the quality
+            let mut best = None;
+            let (mut low, mut high) = (0, 100);
+            while low <= high {
+                let quality = Quality(low + (high - low) / 2);
+                let candidate = ShrinkOptions {
+                    quality,
+                    ..options.clone()
+                };
+                let encoded = encode(image, format, &candidate, metadata)?;
+                let decoded = image::load_from_memory(&encoded).map_err(|e| {
+                    ImageProcessorError::encode(
+                        format,
+                        "Failed to decode the encoded image",
+                        Some(e.into()),
+                    )
+                })?;
+                let score = ssim::ssim(image, &decoded);
+                if score >= min_ssim {
+                    best = Some((encoded, quality, Some(score)));
+                    if quality.0 == 0 {
+                        break;
+                    }
+                    high = quality.0 - 1;
+                } else {
+                    low = quality.0 + 1;
+                }
+            }
+            best.ok_or(ImageProcessorError::TargetSsimUnreachable(min_ssim))
+        }
+    }
+}
+
+/// Encodes the image in the given format, embedding the metadata the format supports
+fn encode(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &ShrinkOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Jpeg => encode_jpeg(image, options, metadata),
+        OutputFormat::Png => encode_png(image, metadata),
+        OutputFormat::WebP => encode_webp(image, false, options.quality.0 as f32, metadata),
+        // libwebp interprets the quality of lossless encodings as compression effort
+        OutputFormat::WebPLossless => encode_webp(image, true, 75.0, metadata),
+        OutputFormat::Avif => encode_avif(image, options.quality, options.speed, metadata),
+    }
+}
+
+fn encode_jpeg(
+    image: &DynamicImage,
+    options: &ShrinkOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    // JPEG has no alpha channel nor high bit depths
+    let (pixels, color_type) = if image.color().has_color() {
+        (image.to_rgb8().into_raw(), jpeg_encoder::ColorType::Rgb)
+    } else {
+        (image.to_luma8().into_raw(), jpeg_encoder::ColorType::Luma)
+    };
+    let (Ok(width), Ok(height)) = (u16::try_from(image.width()), u16::try_from(image.height()))
+    else {
+        return Err(ImageProcessorError::encode(
+            OutputFormat::Jpeg,
+            "Image too large for JPEG",
+            None,
+        ));
+    };
+    let encoding_error = |e: jpeg_encoder::EncodingError| {
+        ImageProcessorError::encode(
+            OutputFormat::Jpeg,
+            "Failed to encode JPEG image",
+            Some(e.into()),
+        )
+    };
+    let mut encoded = Vec::new();
+    // The encoder only accepts qualities from 1 up
+    let mut encoder = jpeg_encoder::Encoder::new(&mut encoded, options.quality.0.max(1));
+    encoder.set_progressive(options.progressive);
+    encoder.set_sampling_factor(options.chroma_subsampling.into());
+    if let Some(icc_profile) = &metadata.icc_profile {
         encoder
-            .encode(&buffer, width, height, color_type)
-            .map_err(|e| {
-                ImageProcessorError::DecodingError(format!("Failed to encode JPEG image: {}", e))
-            })?;
-        Ok(())
+            .add_icc_profile(icc_profile)
+            .map_err(encoding_error)?;
+    }
+    for (number, segment) in metadata::jpeg_app_segments(metadata)? {
+        encoder
+            .add_app_segment(number, segment)
+            .map_err(encoding_error)?;
+    }
+    encoder
+        .encode(&pixels, width, height, color_type)
+        .map_err(encoding_error)?;
+    Ok(encoded)
+}
+
+/// Filters tried when looking for the smallest PNG encoding
+const PNG_FILTERS: [FilterType; 6] = [
+    FilterType::NoFilter,
+    FilterType::Sub,
+    FilterType::Up,
+    FilterType::Avg,
+    FilterType::Paeth,
+    FilterType::Adaptive,
+];
+
+/// Compression levels tried when looking for the smallest PNG encoding
+const PNG_COMPRESSIONS: [CompressionType; 2] = [CompressionType::Best, CompressionType::Level(9)];
+
+/// Encodes the image with every filter and compression combination and returns the smallest
+fn encode_png(image: &DynamicImage, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut smallest: Option<Vec<u8>> = None;
+    for compression in PNG_COMPRESSIONS {
+        for filter in PNG_FILTERS {
+            let mut candidate = Vec::new();
+            let mut encoder = PngEncoder::new_with_quality(&mut candidate, compression, filter);
+            // PNG supports ICC profiles and EXIF metadata, so these cannot fail
+            if let Some(icc_profile) = &metadata.icc_profile {
+                encoder.set_icc_profile(icc_profile.clone()).ok();
+            }
+            if let Some(exif) = &metadata.exif {
+                encoder.set_exif_metadata(exif.clone()).ok();
+            }
+            encoder
+                .write_image(
+                    image.as_bytes(),
+                    image.width(),
+                    image.height(),
+                    image.color().into(),
+                )
+                .map_err(|e| {
+                    ImageProcessorError::encode(
+                        OutputFormat::Png,
+                        "Failed to encode PNG image",
+                        Some(e.into()),
+                    )
+                })?;
+            if smallest.as_ref().is_none_or(|s| candidate.len() < s.len()) {
+                smallest = Some(candidate);
+            }
+        }
+    }
+    Ok(metadata::embed_in_png(
+        smallest.unwrap_or_default(),
+        metadata,
+    ))
+}
+
+fn encode_webp(
+    image: &DynamicImage,
+    lossless: bool,
+    quality: f32,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    // libwebp only accepts 8-bit RGB(A) pixels
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+    let encoder = match &image {
+        DynamicImage::ImageRgba8(pixels) => {
+            webp::Encoder::from_rgba(pixels.as_raw(), image.width(), image.height())
+        }
+        _ => webp::Encoder::from_rgb(image.as_bytes(), image.width(), image.height()),
+    };
+    let encoded = encoder.encode_simple(lossless, quality).map_err(|e| {
+        // The libwebp error codes do not implement the error trait
+        let format = if lossless {
+            OutputFormat::WebPLossless
+        } else {
+            OutputFormat::WebP
+        };
+        ImageProcessorError::encode(
+            format,
+            format!("Failed to encode WebP image: {:?}", e),
+            None,
+        )
+    })?;
+    // The simple libwebp API cannot embed metadata, so the chunks are muxed in afterwards
+    metadata::embed_in_webp(
+        &encoded,
+        image.width(),
+        image.height(),
+        image.color().has_alpha(),
+        metadata,
+    )
+}
+
+fn encode_avif(
+    image: &DynamicImage,
+    quality: Quality,
+    speed: Speed,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    // The AVIF encoder works on 8-bit RGB(A) pixels
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+    let mut encoded = Vec::new();
+    let mut encoder = AvifEncoder::new_with_speed_quality(&mut encoded, speed.0, quality.0);
+    if let Some(exif) = &metadata.exif {
+        // AVIF supports EXIF metadata, so this cannot fail. XMP and IPTC cannot be embedded.
+        encoder.set_exif_metadata(exif.clone()).ok();
     }
+    encoder
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color().into(),
+        )
+        .map_err(|e| {
+            ImageProcessorError::encode(
+                OutputFormat::Avif,
+                "Failed to encode AVIF image",
+                Some(e.into()),
+            )
+        })?;
+    Ok(encoded)
 }
 
 #[cfg(test)]
@@ -105,6 +1259,7 @@ mod tests {
     use std::fs;
 
     use super::*;
+    use image::codecs::jpeg::JpegEncoder;
 
     #[test]
     fn test_quality() {
@@ -116,32 +1271,79 @@ mod tests {
         assert!(quality.is_err());
     }
 
+    #[test]
+    fn test_speed() {
+        assert_eq!(Speed::try_from(10).unwrap().0, 10);
+        assert!(Speed::try_from(0).is_err());
+        assert!(Speed::try_from(11).is_err());
+    }
+
     #[test]
     fn test_image_processor_factory() {
-        let factory = DefaultImageProcessorFactory {};
+        let factory = DefaultImageProcessorFactory::default();
         let processor = factory.process_image(Path::new("test.jpg"));
         assert!(processor.is_ok());
         let processor = factory.process_image(Path::new("test.jpeg"));
         assert!(processor.is_ok());
         let processor = factory.process_image(Path::new("test.png"));
+        assert!(processor.is_ok());
+        let processor = factory.process_image(Path::new("test.gif"));
         assert!(processor.is_err());
     }
 
+    #[test]
+    fn test_format_detection() {
+        let factory = DefaultImageProcessorFactory::default();
+        assert!(factory.process_image(Path::new("test.jpg")).is_ok());
+
+        let uppercase_path = Path::new("/tmp/img-compactor-test-input.JPG");
+        fs::copy("test.jpg", uppercase_path).unwrap();
+        assert!(factory.process_image(uppercase_path).is_ok());
+
+        let extensionless_path = Path::new("/tmp/img-compactor-test-input");
+        fs::copy("test.jpg", extensionless_path).unwrap();
+        assert!(factory.process_image(extensionless_path).is_ok());
+
+        let mismatched_path = Path::new("/tmp/img-compactor-test-mismatch.png");
+        fs::copy("test.jpg", mismatched_path).unwrap();
+        assert!(matches!(
+            factory.process_image(mismatched_path),
+            Err(ImageProcessorError::FormatMismatch {
+                detected: ImageFormat::Jpeg,
+                extension: ImageFormat::Png,
+            })
+        ));
+
+        assert!(matches!(
+            factory.process_image(Path::new("Cargo.toml")),
+            Err(ImageProcessorError::UnsupportedFormat)
+        ));
+
+        // Content is detected in memory as well, where there is no extension to fall back on
+        assert!(
+            factory
+                .process_bytes(&fs::read("test.jpg").unwrap())
+                .is_ok()
+        );
+        assert!(matches!(
+            factory.process_bytes(b"[package]"),
+            Err(ImageProcessorError::UnsupportedFormat)
+        ));
+    }
+
     #[test]
     fn test_jpeg_processor() {
         let input_path = Path::new("test.jpg");
         let processor = JpegProcessor {
-            input_path: input_path.to_path_buf(),
+            source: ImageSource::File(input_path.to_path_buf()),
         };
         let output_path = Path::new("/tmp/img-compactor-test-output.jpg");
-        fs::remove_file(&output_path).ok();
+        fs::remove_file(output_path).ok();
         let quality = Quality::try_from(50).unwrap();
-        let result = processor.shrink_to(output_path, quality);
+        let result = processor.shrink_to(output_path, &quality.into());
         assert!(result.is_ok());
         assert!(output_path.exists());
-        assert!(
-            fs::metadata(&output_path).unwrap().len() < fs::metadata(&input_path).unwrap().len()
-        );
+        assert!(fs::metadata(output_path).unwrap().len() < fs::metadata(input_path).unwrap().len());
     }
 
     #[test]
@@ -152,25 +1354,836 @@ mod tests {
 
         // Test non-existent input file
         let processor = JpegProcessor {
-            input_path: input_path.to_path_buf(),
+            source: ImageSource::File(input_path.to_path_buf()),
         };
-        let result = processor.shrink_to(output_path, quality);
+        let result = processor.shrink_to(output_path, &quality.into());
         assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(ImageProcessorError::ReadInput { path: Some(path), .. }) if path == input_path
+        ));
 
         // Test unsupported format
         let unsupported_path = Path::new("Cargo.toml");
         let processor = JpegProcessor {
-            input_path: unsupported_path.to_path_buf(),
+            source: ImageSource::File(unsupported_path.to_path_buf()),
         };
-        let result = processor.shrink_to(output_path, quality);
+        let result = processor.shrink_to(output_path, &quality.into());
         assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert!(matches!(
+            &error,
+            ImageProcessorError::Decode { path: Some(path), source: Some(_), .. }
+                if path == unsupported_path
+        ));
+        assert!(error.to_string().contains("Cargo.toml"));
+        assert!(std::error::Error::source(&error).is_some());
 
         // Test wrong output path
         let wrong_output_path = Path::new("/non_writable_dir/output.jpg");
         let processor = JpegProcessor {
-            input_path: input_path.to_path_buf(),
+            source: ImageSource::File(input_path.to_path_buf()),
         };
-        let result = processor.shrink_to(wrong_output_path, quality);
+        let result = processor.shrink_to(wrong_output_path, &quality.into());
         assert!(result.is_err());
+        let processor = JpegProcessor {
+            source: ImageSource::File(Path::new("test.jpg").to_path_buf()),
+        };
+        let result = processor.shrink_to(wrong_output_path, &quality.into());
+        assert!(matches!(
+            result,
+            Err(ImageProcessorError::WriteOutput { path: Some(path), .. })
+                if path == wrong_output_path
+        ));
+
+        // Failures of in-memory input carry no path
+        let processor = JpegProcessor {
+            source: ImageSource::Memory(b"\xff\xd8 truncated".to_vec()),
+        };
+        let result = processor.shrink_into(&mut Vec::new(), &quality.into());
+        assert!(matches!(
+            result,
+            Err(ImageProcessorError::Decode { path: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_decompression_bomb() {
+        // A tiny JPEG whose frame header claims 65535x65535 pixels
+        let mut input = Vec::new();
+        jpeg_encoder::Encoder::new(&mut input, 50)
+            .encode(&[0; 192], 8, 8, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        let sof = input.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        input[sof + 5..sof + 9].copy_from_slice(&[0xff; 4]);
+        let processor = JpegProcessor {
+            source: ImageSource::Memory(input),
+        };
+        let options = ShrinkOptions::new(Quality(50));
+        for options in [options.clone(), options.with_lossless(true)] {
+            let result = processor.shrink_into(&mut Vec::new(), &options);
+            assert!(matches!(
+                result,
+                Err(ImageProcessorError::LimitExceeded { .. })
+            ));
+        }
+
+        let processor = JpegProcessor {
+            source: ImageSource::File(Path::new("test.jpg").to_path_buf()),
+        };
+        let options =
+            ShrinkOptions::new(Quality(50)).with_limits(Limits::default().with_max_pixels(1000));
+        let result = processor.shrink_into(&mut Vec::new(), &options);
+        assert!(matches!(
+            result,
+            Err(ImageProcessorError::LimitExceeded { path: Some(_), .. })
+        ));
+
+        // The coefficients of the 13x9 blocks of each of the three components of test.jpg
+        // take two bytes each in lossless mode, more than its 20400 bytes of pixels
+        let lossless = |max_alloc| {
+            let options = ShrinkOptions::new(Quality(50))
+                .with_lossless(true)
+                .with_limits(Limits::default().with_max_alloc(max_alloc));
+            processor.shrink_into(&mut Vec::new(), &options)
+        };
+        assert!(matches!(
+            lossless(3 * 13 * 9 * 128 - 1),
+            Err(ImageProcessorError::LimitExceeded { .. })
+        ));
+        assert!(lossless(3 * 13 * 9 * 128).is_ok());
+
+        // A frame header claiming more blocks than the scan holds passes the check when they
+        // fit, the scan is then decoded into the checked buffers, see tests/lossless_memory.rs
+        let mut input = Vec::new();
+        jpeg_encoder::Encoder::new(&mut input, 50)
+            .encode(&[0; 192], 8, 8, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        let sof = input.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        input[sof + 5..sof + 9].copy_from_slice(&[0x01, 0x00, 0x01, 0x00]);
+        let processor = JpegProcessor {
+            source: ImageSource::Memory(input),
+        };
+        let coefficients = (32 * 32 + 2 * 16 * 16) * 128;
+        for (max_alloc, rejected) in [(coefficients - 1, true), (coefficients, false)] {
+            let options = ShrinkOptions::new(Quality(50))
+                .with_lossless(true)
+                .with_limits(Limits::default().with_max_alloc(max_alloc));
+            let result = processor.shrink_into(&mut Vec::new(), &options);
+            let exceeded = matches!(result, Err(ImageProcessorError::LimitExceeded { .. }));
+            assert_eq!(exceeded, rejected);
+        }
+    }
+
+    #[test]
+    fn test_jpeg_encoding_options() {
+        // Marker of the frame header and sampling factors of its first component
+        let frame_of = |encoded: &[u8]| {
+            let mut at = 2;
+            while ![0xc0, 0xc2].contains(&encoded[at + 1]) {
+                at += 2 + u16::from_be_bytes([encoded[at + 2], encoded[at + 3]]) as usize;
+            }
+            (encoded[at + 1], encoded[at + 11])
+        };
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_image(Path::new("test.jpg")).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(80).unwrap());
+        for (options, frame) in [
+            (options.clone(), (0xc0, 0x22)),
+            (
+                options
+                    .clone()
+                    .with_progressive(true)
+                    .with_chroma_subsampling(ChromaSubsampling::Ratio444),
+                (0xc2, 0x11),
+            ),
+            (
+                options.with_chroma_subsampling("4:2:2".parse().unwrap()),
+                (0xc0, 0x21),
+            ),
+        ] {
+            let mut output = Vec::new();
+            processor.shrink_into(&mut output, &options).unwrap();
+            assert_eq!(frame_of(&output), frame);
+            assert_eq!(
+                image::load_from_memory(&output).unwrap().dimensions(),
+                (100, 68)
+            );
+        }
+        assert!("4:1:1".parse::<ChromaSubsampling>().is_err());
+    }
+
+    #[test]
+    fn test_lossless_jpeg() {
+        let input = fs::read("test.jpg").unwrap();
+        let processor = JpegProcessor {
+            source: ImageSource::Memory(input.clone()),
+        };
+        let options = ShrinkOptions::new(Quality(10)).with_lossless(true);
+        let mut output = Vec::new();
+        let report = processor.shrink_into(&mut output, &options).unwrap();
+        // Nothing is measured nor searched for without re-encoding
+        assert_eq!(report.quality, Quality(10));
+        assert_eq!(report.ssim, None);
+        assert!(output.len() < input.len());
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgb8(),
+            image::load_from_memory(&input).unwrap().to_rgb8()
+        );
+        assert_eq!(exif_of(&output), exif_of(&input));
+
+        for options in [
+            options.clone().with_format(OutputFormat::WebP),
+            options.clone().with_resize(Resize::new(Some(10), None)),
+            options.clone().with_target(QualityTarget::MaxSize(1000)),
+            options.clone().with_convert_to_srgb(true),
+        ] {
+            let result = processor.shrink_into(&mut Vec::new(), &options);
+            assert!(matches!(result, Err(ImageProcessorError::InvalidValue(_))));
+        }
+    }
+
+    #[test]
+    fn test_png_processor() {
+        let input_path = Path::new("/tmp/img-compactor-test-input.png");
+        let image = image::RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([x as u8, y as u8, ((x + y) / 2) as u8])
+        });
+        let mut encoded = Vec::new();
+        PngEncoder::new_with_quality(&mut encoded, CompressionType::Fast, FilterType::NoFilter)
+            .write_image(image.as_raw(), 256, 256, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        fs::write(input_path, &encoded).unwrap();
+
+        let processor = PngProcessor {
+            source: ImageSource::File(input_path.to_path_buf()),
+        };
+        let output_path = Path::new("/tmp/img-compactor-test-output.png");
+        fs::remove_file(output_path).ok();
+        let quality = Quality::try_from(50).unwrap();
+        let result = processor.shrink_to(output_path, &quality.into());
+        assert!(result.is_ok());
+        assert!(fs::metadata(output_path).unwrap().len() < encoded.len() as u64);
+        let decoded = image::load_from_memory(&fs::read(output_path).unwrap()).unwrap();
+        assert_eq!(decoded.to_rgb8(), image);
+
+        // Already optimal input is passed through unchanged
+        fs::copy(output_path, input_path).unwrap();
+        let result = processor.shrink_to(output_path, &quality.into());
+        assert!(result.is_ok());
+        assert_eq!(
+            fs::read(output_path).unwrap(),
+            fs::read(input_path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        let directory = Path::new("/tmp/img-compactor-test-atomic");
+        fs::remove_dir_all(directory).ok();
+        fs::create_dir_all(directory).unwrap();
+        let output_path = directory.join("output.jpg");
+        fs::write(&output_path, b"previous output").unwrap();
+        let processor = JpegProcessor {
+            source: ImageSource::File(Path::new("test.jpg").to_path_buf()),
+        };
+
+        // A failing shrink leaves the destination alone and cleans up
+        let options = ShrinkOptions::new(Quality(50))
+            .with_lossless(true)
+            .with_resize(Resize::new(Some(10), None));
+        assert!(processor.shrink_to(&output_path, &options).is_err());
+        assert_eq!(fs::read(&output_path).unwrap(), b"previous output");
+        assert_eq!(fs::read_dir(directory).unwrap().count(), 1);
+
+        let options = ShrinkOptions::new(Quality(50));
+        let report = processor.shrink_to(&output_path, &options).unwrap();
+        assert_eq!(report.outcome, ShrinkOutcome::Written);
+        let output = fs::read(&output_path).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(fs::read_dir(directory).unwrap().count(), 1);
+
+        // Pending outputs are only visible once persisted, under the name chosen then
+        let (_, pending) = processor.shrink_pending(&output_path, &options).unwrap();
+        assert_eq!(fs::read_dir(directory).unwrap().count(), 2);
+        drop(pending);
+        assert_eq!(fs::read_dir(directory).unwrap().count(), 1);
+        let (_, pending) = processor.shrink_pending(&output_path, &options).unwrap();
+        let renamed = directory.join("renamed.jpg");
+        pending.unwrap().persist(&renamed).unwrap();
+        assert_eq!(fs::read(&renamed).unwrap(), output);
+        assert_eq!(fs::read_dir(directory).unwrap().count(), 2);
+        fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!("JPG".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg);
+        assert_eq!("webp".parse::<OutputFormat>().unwrap(), OutputFormat::WebP);
+        assert_eq!(
+            "webp-lossless".parse::<OutputFormat>().unwrap(),
+            OutputFormat::WebPLossless
+        );
+        assert!("bmp".parse::<OutputFormat>().is_err());
+        assert_eq!(OutputFormat::WebPLossless.extension(), "webp");
+    }
+
+    #[test]
+    fn test_webp_transcoding() {
+        let input_path = Path::new("test.jpg");
+        let processor = JpegProcessor {
+            source: ImageSource::File(input_path.to_path_buf()),
+        };
+        let quality = Quality::try_from(50).unwrap();
+
+        let output_path = Path::new("/tmp/img-compactor-test-output-lossy.webp");
+        fs::remove_file(output_path).ok();
+        let options = ShrinkOptions::new(quality).with_format(OutputFormat::WebP);
+        assert!(processor.shrink_to(output_path, &options).is_ok());
+        let output = fs::read(output_path).unwrap();
+        assert_eq!(
+            image::guess_format(&output).unwrap(),
+            image::ImageFormat::WebP
+        );
+        assert!(output.len() < fs::metadata(input_path).unwrap().len() as usize);
+
+        let output_path = Path::new("/tmp/img-compactor-test-output-lossless.webp");
+        fs::remove_file(output_path).ok();
+        // Lossless output of a lossy input is larger
+        let options = ShrinkOptions::new(quality)
+            .with_format(OutputFormat::WebPLossless)
+            .with_larger_output(LargerOutput::Keep);
+        assert!(processor.shrink_to(output_path, &options).is_ok());
+        let output = fs::read(output_path).unwrap();
+        assert_eq!(&output[8..12], b"WEBP");
+        assert!(output.windows(4).any(|chunk| chunk == b"VP8L"));
+
+        // Metadata cannot be embedded in an empty canvas
+        let metadata = Metadata {
+            icc_profile: None,
+            exif: Some(b"MM\0*\0\0\0\x08\0\0\0\0\0\0".to_vec()),
+            xmp: None,
+            iptc: None,
+            orientation: Orientation::NoTransforms,
+        };
+        for (width, height) in [(0, 16), (16, 0), (1 << 25, 16)] {
+            let result = metadata::embed_in_webp(&output, width, height, false, &metadata);
+            assert!(matches!(result, Err(ImageProcessorError::Encode { .. })));
+        }
+    }
+
+    #[test]
+    fn test_larger_output() {
+        let input = fs::read("test.jpg").unwrap();
+        let processor = JpegProcessor {
+            source: ImageSource::Memory(input.clone()),
+        };
+        let options = ShrinkOptions::new(Quality(100));
+
+        let mut output = Vec::new();
+        let keep = options.clone().with_larger_output(LargerOutput::Keep);
+        let report = processor.shrink_into(&mut output, &keep).unwrap();
+        assert_eq!(report.outcome, ShrinkOutcome::Written);
+        assert!(output.len() > input.len());
+        let output_size = output.len() as u64;
+
+        let mut output = Vec::new();
+        let report = processor.shrink_into(&mut output, &options).unwrap();
+        assert_eq!(report.outcome, ShrinkOutcome::CopiedOriginal);
+        assert_eq!(output, input);
+
+        // The original cannot stand in for another format
+        let mut output = Vec::new();
+        let lossless = options.clone().with_format(OutputFormat::WebPLossless);
+        let report = processor.shrink_into(&mut output, &lossless).unwrap();
+        assert_eq!(report.outcome, ShrinkOutcome::NotSmaller);
+        assert!(output.is_empty());
+
+        // Nor for a JPEG encoding asked for explicitly
+        for options in [
+            options.clone().with_progressive(true),
+            options
+                .clone()
+                .with_chroma_subsampling(ChromaSubsampling::Ratio444),
+            ShrinkOptions::new(Quality(100))
+                .with_lossless(true)
+                .with_progressive(true),
+        ] {
+            let mut output = Vec::new();
+            let report = processor.shrink_into(&mut output, &options).unwrap();
+            assert_eq!(report.outcome, ShrinkOutcome::NotSmaller, "{:?}", options);
+            assert!(output.is_empty());
+        }
+
+        let output_path = Path::new("/tmp/img-compactor-test-output-larger.jpg");
+        fs::remove_file(output_path).ok();
+        let skip = options.with_larger_output(LargerOutput::Skip);
+        let report = processor.shrink_to(output_path, &skip).unwrap();
+        assert_eq!(report.outcome, ShrinkOutcome::NotSmaller);
+        assert_eq!(report.input_size, input.len() as u64);
+        assert_eq!(report.output_size, output_size);
+        assert_eq!(report.written_size(), 0);
+        assert!(!output_path.exists());
+        assert_eq!(
+            "copy".parse::<LargerOutput>().unwrap(),
+            LargerOutput::CopyOriginal
+        );
+        assert!("bigger".parse::<LargerOutput>().is_err());
+    }
+
+    #[test]
+    fn test_avif_transcoding() {
+        let processor = JpegProcessor {
+            source: ImageSource::File(Path::new("test.jpg").to_path_buf()),
+        };
+        let output_path = Path::new("/tmp/img-compactor-test-output.avif");
+        fs::remove_file(output_path).ok();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_speed(Speed::try_from(10).unwrap())
+            .with_format(OutputFormat::Avif);
+        assert!(processor.shrink_to(output_path, &options).is_ok());
+        let output = fs::read(output_path).unwrap();
+        assert_eq!(&output[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn test_in_memory_processing() {
+        let factory = DefaultImageProcessorFactory::default();
+        let input = fs::read("test.jpg").unwrap();
+        let quality = Quality::try_from(50).unwrap();
+
+        let processor = factory.process_bytes(&input).unwrap();
+        let mut output = Vec::new();
+        assert!(processor.shrink_into(&mut output, &quality.into()).is_ok());
+        assert_eq!(image::guess_format(&output).unwrap(), ImageFormat::Jpeg);
+        assert!(output.len() < input.len());
+
+        let processor = factory.process_reader(Cursor::new(&input)).unwrap();
+        let mut reader_output = Vec::new();
+        assert!(
+            processor
+                .shrink_into(&mut reader_output, &quality.into())
+                .is_ok()
+        );
+        assert_eq!(reader_output, output);
+
+        assert!(matches!(
+            factory.process_bytes(b"not an image").err(),
+            Some(ImageProcessorError::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn test_resizing() {
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_image(Path::new("test.jpg")).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_resize(Resize::new(Some(64), Some(64)));
+        let mut output = Vec::new();
+        assert!(processor.shrink_into(&mut output, &options).is_ok());
+        let (width, height) = image::load_from_memory(&output).unwrap().dimensions();
+        assert!(width <= 64 && height <= 64);
+        assert!(width == 64 || height == 64);
+    }
+
+    #[test]
+    fn test_shrink_report() {
+        let input = fs::read("test.jpg").unwrap();
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let options = ShrinkOptions::new(Quality(50))
+            .with_format(OutputFormat::WebP)
+            .with_resize(Resize::new(Some(50), None));
+        let mut output = Vec::new();
+        let report = processor.shrink_into(&mut output, &options).unwrap();
+        assert_eq!(report.input_size, input.len() as u64);
+        assert_eq!(report.output_size, output.len() as u64);
+        assert_eq!(report.written_size(), output.len() as u64);
+        assert_eq!(report.input_dimensions, (100, 68));
+        assert_eq!(report.output_dimensions, (50, 34));
+        assert_eq!(report.color_type, ColorType::Rgb8);
+        assert_eq!(report.format, OutputFormat::WebP);
+        assert_eq!(report.quality, Quality(50));
+        assert_eq!(report.outcome, ShrinkOutcome::Written);
+        assert!(report.decode_time > Duration::ZERO);
+        assert!(report.encode_time > Duration::ZERO);
+
+        let options = ShrinkOptions::new(Quality(50)).with_lossless(true);
+        let report = processor.shrink_into(&mut Vec::new(), &options).unwrap();
+        assert_eq!(report.output_dimensions, (100, 68));
+        assert_eq!(report.format, OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_target_size() {
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_image(Path::new("test.jpg")).unwrap();
+        let max_size = 3000;
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_metadata_policy(MetadataPolicy::StripAll)
+            .with_target(QualityTarget::MaxSize(max_size));
+        let mut output = Vec::new();
+        let report = processor.shrink_into(&mut output, &options).unwrap();
+        assert!(output.len() as u64 <= max_size);
+
+        // The next quality step no longer fits
+        let higher = Quality::try_from(report.quality.0 as u64 + 1).unwrap();
+        let mut higher_output = Vec::new();
+        processor
+            .shrink_into(
+                &mut higher_output,
+                &ShrinkOptions::new(higher).with_metadata_policy(MetadataPolicy::StripAll),
+            )
+            .unwrap();
+        assert!(higher_output.len() as u64 > max_size);
+
+        let options = options.with_target(QualityTarget::MaxSize(10));
+        assert!(matches!(
+            processor.shrink_into(&mut Vec::new(), &options),
+            Err(ImageProcessorError::TargetSizeUnreachable(10))
+        ));
+    }
+
+    #[test]
+    fn test_target_ssim() {
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_image(Path::new("test.jpg")).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_target(QualityTarget::MinSsim(0.95));
+        let mut output = Vec::new();
+        let report = processor.shrink_into(&mut output, &options).unwrap();
+        assert!(report.ssim.unwrap() >= 0.95);
+        assert!(report.quality.0 < 100);
+
+        let webp_options = options.clone().with_format(OutputFormat::WebP);
+        let report = processor
+            .shrink_into(&mut Vec::new(), &webp_options)
+            .unwrap();
+        assert!(report.ssim.unwrap() >= 0.95);
+
+        let avif_options = options.clone().with_format(OutputFormat::Avif);
+        assert!(
+            processor
+                .shrink_into(&mut Vec::new(), &avif_options)
+                .is_err()
+        );
+
+        let unreachable = options.with_target(QualityTarget::MinSsim(1.5));
+        assert!(matches!(
+            processor.shrink_into(&mut Vec::new(), &unreachable),
+            Err(ImageProcessorError::TargetSsimUnreachable(_))
+        ));
+    }
+
+    fn exif_of(encoded: &[u8]) -> Option<Vec<u8>> {
+        let format = image::guess_format(encoded).unwrap();
+        let mut decoder = image::ImageReader::with_format(Cursor::new(encoded), format)
+            .into_decoder()
+            .unwrap();
+        decoder.exif_metadata().unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn icc_profile_of(encoded: &[u8]) -> Option<Vec<u8>> {
+        let format = image::guess_format(encoded).unwrap();
+        let mut decoder = image::ImageReader::with_format(Cursor::new(encoded), format)
+            .into_decoder()
+            .unwrap();
+        decoder.icc_profile().unwrap()
+    }
+
+    #[test]
+    fn test_exif_preservation() {
+        let input = fs::read("test.jpg").unwrap();
+        let exif = exif_of(&input);
+        assert!(exif.is_some());
+
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_larger_output(LargerOutput::Keep);
+        for format in [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP] {
+            let mut output = Vec::new();
+            processor
+                .shrink_into(&mut output, &options.clone().with_format(format))
+                .unwrap();
+            assert_eq!(exif_of(&output), exif, "{:?}", format);
+        }
+
+        let mut output = Vec::new();
+        processor
+            .shrink_into(
+                &mut output,
+                &options.with_metadata_policy(MetadataPolicy::StripAll),
+            )
+            .unwrap();
+        assert_eq!(exif_of(&output), None);
+    }
+
+    #[test]
+    fn test_metadata_policy() {
+        let xmp = concat!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF>",
+            "<rdf:Description rdf:about=\"\" xmp:CreatorTool=\"GIMP\" dc:format=\"image/jpeg\"/>",
+            "</rdf:RDF></x:xmpmeta>",
+        );
+        let original = fs::read("test.jpg").unwrap();
+        let metadata = Metadata {
+            icc_profile: None,
+            exif: exif_of(&original),
+            xmp: Some(xmp.as_bytes().to_vec()),
+            iptc: None,
+            orientation: Orientation::NoTransforms,
+        };
+        let image = image::load_from_memory(&original).unwrap();
+        let input = encode_jpeg(&image, &ShrinkOptions::new(Quality(90)), &metadata).unwrap();
+
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_larger_output(LargerOutput::Keep);
+        let policy: MetadataPolicy = "deny:Make,Model,GPS*,xmp:CreatorTool".parse().unwrap();
+        for format in [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP] {
+            let options = options
+                .clone()
+                .with_format(format)
+                .with_metadata_policy(policy.clone());
+            let mut output = Vec::new();
+            processor.shrink_into(&mut output, &options).unwrap();
+            let exif = exif_of(&output).unwrap();
+            assert!(!contains(&exif, b"Canon"), "{:?}", format);
+            assert!(contains(&exif, b"2008:05:30 15:56:01"), "{:?}", format);
+            let detected = image::guess_format(&output).unwrap();
+            let mut decoder = image::ImageReader::with_format(Cursor::new(&output), detected)
+                .into_decoder()
+                .unwrap();
+            let xmp = decoder.xmp_metadata().unwrap().unwrap();
+            assert!(!contains(&xmp, b"CreatorTool"), "{:?}", format);
+            assert!(contains(&xmp, b"dc:format"), "{:?}", format);
+        }
+
+        let mut output = Vec::new();
+        let allow = options.with_metadata_policy("allow:DateTimeOriginal".parse().unwrap());
+        processor.shrink_into(&mut output, &allow).unwrap();
+        let exif = exif_of(&output).unwrap();
+        assert!(exif.len() < 100);
+        assert!(contains(&exif, b"2008:05:30 15:56:01"));
+        assert!(!contains(&output, b"GIMP"));
+    }
+
+    #[test]
+    fn test_large_metadata() {
+        let xmp = format!(
+            concat!(
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF>",
+                "<rdf:Description rdf:about=\"\" xmp:CreatorTool=\"GIMP\" ",
+                "dc:format=\"image/jpeg\"><dc:description>{}</dc:description>",
+                "</rdf:Description></rdf:RDF></x:xmpmeta>",
+            ),
+            "Long caption ".repeat(10000)
+        );
+        // Profiles are split across APP2 segments, the padding makes it need three
+        let mut icc_profile = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        icc_profile.resize(150_000, 0);
+        let size = icc_profile.len() as u32;
+        icc_profile[0..4].copy_from_slice(&size.to_be_bytes());
+        let metadata = Metadata {
+            icc_profile: Some(icc_profile.clone()),
+            exif: None,
+            xmp: Some(xmp.clone().into_bytes()),
+            iptc: None,
+            orientation: Orientation::NoTransforms,
+        };
+        let image = image::load_from_memory(&fs::read("test.jpg").unwrap()).unwrap();
+        let input = encode_jpeg(&image, &ShrinkOptions::new(Quality(90)), &metadata).unwrap();
+        assert!(contains(&input, b"http://ns.adobe.com/xmp/extension/\0"));
+
+        let metadata_of = |encoded: &[u8]| {
+            let mut decoder = JpegDecoder::new(Cursor::new(encoded)).unwrap();
+            let mut metadata = Metadata::read(&mut decoder).unwrap();
+            metadata.xmp = metadata.xmp.map(|xmp| {
+                metadata::merge_extended_xmp(xmp, lossless::app1_payloads(encoded).into_iter())
+            });
+            metadata
+        };
+        let read = metadata_of(&input);
+        assert_eq!(read.icc_profile, Some(icc_profile.clone()));
+        let read_xmp = read.xmp.unwrap();
+        let description =
+            &xmp[xmp.find("<rdf:Description").unwrap()..xmp.find("</rdf:RDF>").unwrap()];
+        assert!(contains(&read_xmp, description.as_bytes()));
+        assert!(!contains(&read_xmp, b"HasExtendedXMP"));
+        // Incomplete extended XMP is ignored
+        let mut segments = lossless::app1_payloads(&input);
+        segments.pop();
+        let main = segments
+            .iter()
+            .find_map(|segment| segment.strip_prefix(b"http://ns.adobe.com/xap/1.0/\0"))
+            .unwrap();
+        let merged = metadata::merge_extended_xmp(main.to_vec(), segments.into_iter());
+        assert_eq!(merged, main);
+
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let policy: MetadataPolicy = "deny:xmp:CreatorTool".parse().unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_larger_output(LargerOutput::Keep)
+            .with_metadata_policy(policy);
+        for options in [options.clone(), options.with_lossless(true)] {
+            let mut output = Vec::new();
+            processor.shrink_into(&mut output, &options).unwrap();
+            let read = metadata_of(&output);
+            assert_eq!(read.icc_profile.as_ref(), Some(&icc_profile));
+            let read_xmp = read.xmp.unwrap();
+            assert!(contains(&read_xmp, b"dc:format"));
+            assert!(contains(
+                &read_xmp,
+                "Long caption ".repeat(10000).as_bytes()
+            ));
+            assert!(!contains(&read_xmp, b"CreatorTool"));
+        }
+
+        // EXIF cannot be split across segments
+        let metadata = Metadata {
+            icc_profile: None,
+            exif: Some(vec![0; 70_000]),
+            xmp: None,
+            iptc: None,
+            orientation: Orientation::NoTransforms,
+        };
+        let result = encode_jpeg(&image, &ShrinkOptions::new(Quality(90)), &metadata);
+        assert!(matches!(result, Err(ImageProcessorError::Encode { .. })));
+    }
+
+    #[test]
+    fn test_orientation() {
+        // Minimal little endian EXIF block with the orientation "rotate 90° clockwise"
+        let exif = [
+            b"II*\0".as_slice(),
+            &8u32.to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &[0x12, 0x01, 3, 0],
+            &1u32.to_le_bytes(),
+            &[6, 0, 0, 0],
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+        let pixels = image::RgbImage::from_fn(40, 20, |x, _| image::Rgb([x as u8 * 6, 0, 0]));
+        let mut input = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut input, 90);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .encode(pixels.as_raw(), 40, 20, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let mut output = Vec::new();
+        processor
+            .shrink_into(&mut output, &Quality::try_from(90).unwrap().into())
+            .unwrap();
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded.dimensions(), (20, 40));
+        let exif = exif_of(&output).unwrap();
+        assert_eq!(
+            Orientation::from_exif_chunk(&exif),
+            Some(Orientation::NoTransforms)
+        );
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let icc_profile = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        let pixels = image::RgbImage::from_pixel(16, 16, image::Rgb([40, 200, 60]));
+        let mut input = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut input, 90);
+        encoder.set_icc_profile(icc_profile.clone()).unwrap();
+        encoder
+            .encode(pixels.as_raw(), 16, 16, image::ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let factory = DefaultImageProcessorFactory::default();
+        let processor = factory.process_bytes(&input).unwrap();
+        let options = ShrinkOptions::new(Quality::try_from(90).unwrap());
+        for format in [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP] {
+            let mut output = Vec::new();
+            processor
+                .shrink_into(&mut output, &options.clone().with_format(format))
+                .unwrap();
+            assert_eq!(icc_profile_of(&output).as_ref(), Some(&icc_profile));
+        }
+
+        let mut output = Vec::new();
+        processor
+            .shrink_into(&mut output, &options.with_convert_to_srgb(true))
+            .unwrap();
+        assert_eq!(icc_profile_of(&output), None);
+        // The green of Display P3 is more saturated than the one of sRGB
+        let image::Rgb([red, green, blue]) = *image::load_from_memory(&output)
+            .unwrap()
+            .to_rgb8()
+            .get_pixel(8, 8);
+        assert!(green > 200 && red < 40 && blue < 60);
+    }
+
+    #[test]
+    fn test_mismatched_icc_profile() {
+        let shrink = |input: &[u8], options: &ShrinkOptions| {
+            let mut output = Vec::new();
+            let factory = DefaultImageProcessorFactory::default();
+            let processor = factory.process_bytes(input).unwrap();
+            processor.shrink_into(&mut output, options).unwrap();
+            output
+        };
+        let options = ShrinkOptions::new(Quality(90)).with_larger_output(LargerOutput::Keep);
+
+        // The decoder turns CMYK JPEGs into RGB, which their CMYK profile does not describe
+        let mut cmyk_profile = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        cmyk_profile[16..20].copy_from_slice(b"CMYK");
+        let mut input = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut input, 90);
+        encoder.add_icc_profile(&cmyk_profile).unwrap();
+        encoder
+            .encode(
+                &[20, 200, 60, 10].repeat(256),
+                16,
+                16,
+                jpeg_encoder::ColorType::Cmyk,
+            )
+            .unwrap();
+        assert_eq!(
+            icc_profile_of(&input).as_deref(),
+            Some(cmyk_profile.as_slice())
+        );
+        for options in [
+            options.clone(),
+            options.clone().with_format(OutputFormat::Png),
+            options.clone().with_convert_to_srgb(true),
+        ] {
+            assert_eq!(icc_profile_of(&shrink(&input, &options)), None);
+        }
+
+        // Gray images keep gray profiles unless WebP turns them into RGB, and drop RGB ones
+        let gray_profile = moxcms::ColorProfile::new_gray_with_gamma(2.2)
+            .encode()
+            .unwrap();
+        let pixels = image::GrayImage::from_fn(16, 16, |x, y| image::Luma([(x * y) as u8]));
+        for (icc_profile, kept) in [(gray_profile, true), (cmyk_profile, false)] {
+            let mut input = Vec::new();
+            let mut encoder = PngEncoder::new(&mut input);
+            encoder.set_icc_profile(icc_profile.clone()).unwrap();
+            encoder
+                .write_image(pixels.as_raw(), 16, 16, image::ExtendedColorType::L8)
+                .unwrap();
+            let output = shrink(&input, &options);
+            assert_eq!(icc_profile_of(&output), kept.then_some(icc_profile));
+            let output = shrink(&input, &options.clone().with_format(OutputFormat::WebP));
+            assert_eq!(icc_profile_of(&output), None);
+        }
     }
 }
@@ -0,0 +1,141 @@
+use crate::{ImageProcessorError, Result};
+use image::ImageDecoder;
+
+/// Resource limits checked against the image header before any pixels are decoded, so
+/// that a small file claiming huge dimensions cannot exhaust the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum width in pixels
+    pub max_width: u32,
+    /// Maximum height in pixels
+    pub max_height: u32,
+    /// Maximum number of pixels
+    pub max_pixels: u64,
+    /// Maximum number of bytes the decoded pixels and the decoder may allocate
+    pub max_alloc: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 100_000_000,
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Limits that never reject an image
+    pub fn none() -> Self {
+        Limits {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_alloc: u64::MAX,
+        }
+    }
+
+    /// Sets the maximum width and height in pixels
+    pub fn with_max_dimensions(mut self, max_width: u32, max_height: u32) -> Self {
+        self.max_width = max_width;
+        self.max_height = max_height;
+        self
+    }
+
+    /// Sets the maximum number of pixels
+    pub fn with_max_pixels(mut self, max_pixels: u64) -> Self {
+        self.max_pixels = max_pixels;
+        self
+    }
+
+    /// Sets the maximum number of bytes allocated for decoding
+    pub fn with_max_alloc(mut self, max_alloc: u64) -> Self {
+        self.max_alloc = max_alloc;
+        self
+    }
+
+    /// Checks the dimensions and decoded size announced by the header, then hands the
+    /// limits to the decoder for its own allocations
+    pub(crate) fn apply(&self, decoder: &mut impl ImageDecoder) -> Result<()> {
+        let (width, height) = decoder.dimensions();
+        let exceeded = |message: String| {
+            Err(ImageProcessorError::LimitExceeded {
+                path: None,
+                message,
+            })
+        };
+        if width > self.max_width || height > self.max_height {
+            return exceeded(format!(
+                "{}x{} pixels exceed the maximum dimensions of {}x{}",
+                width, height, self.max_width, self.max_height
+            ));
+        }
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_pixels {
+            return exceeded(format!(
+                "{} pixels exceed the maximum of {}",
+                pixels, self.max_pixels
+            ));
+        }
+        self.check_alloc(decoder.total_bytes(), "decoded pixels")?;
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        decoder
+            .set_limits(limits)
+            .map_err(|e| ImageProcessorError::from_image("Failed to set decoder limits", e))
+    }
+
+    /// Checks an allocation of the given number of bytes, for what is described, against
+    /// the maximum before it is made
+    pub(crate) fn check_alloc(&self, bytes: u64, what: &str) -> Result<()> {
+        if bytes > self.max_alloc {
+            return Err(ImageProcessorError::LimitExceeded {
+                path: None,
+                message: format!(
+                    "{} bytes of {} exceed the maximum allocation of {} bytes",
+                    bytes, what, self.max_alloc
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegDecoder;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_limits() {
+        // test.jpg has 100x68 pixels of RGB
+        let input = std::fs::read("test.jpg").unwrap();
+        let decoder = || JpegDecoder::new(Cursor::new(&input)).unwrap();
+        assert!(Limits::default().apply(&mut decoder()).is_ok());
+        assert!(Limits::none().apply(&mut decoder()).is_ok());
+        let rejected = [
+            Limits::default().with_max_dimensions(99, 1000),
+            Limits::default().with_max_dimensions(1000, 67),
+            Limits::default().with_max_pixels(6799),
+            Limits::default().with_max_alloc(20399),
+        ];
+        for limits in rejected {
+            let result = limits.apply(&mut decoder());
+            assert!(
+                matches!(result, Err(ImageProcessorError::LimitExceeded { .. })),
+                "{:?}",
+                limits
+            );
+        }
+        let limits = Limits::default()
+            .with_max_dimensions(100, 68)
+            .with_max_pixels(6800)
+            .with_max_alloc(20400);
+        assert!(limits.apply(&mut decoder()).is_ok());
+    }
+}
@@ -0,0 +1,991 @@
+use crate::{
+    ImageProcessorError, Limits, Result,
+    metadata::{self, Metadata},
+    policy::MetadataPolicy,
+};
+use image::metadata::Orientation;
+use std::rc::Rc;
+
+/// Marker segment of a JPEG file, with the entropy-coded data following a start of scan
+struct Segment<'a> {
+    marker: u8,
+    payload: &'a [u8],
+    entropy: &'a [u8],
+}
+
+/// Splits a JPEG file into its marker segments
+fn split_segments(input: &[u8]) -> Result<Vec<Segment<'_>>> {
+    let malformed = || ImageProcessorError::malformed("Malformed JPEG structure");
+    if !input.starts_with(&[0xff, 0xd8]) {
+        return Err(malformed());
+    }
+    let mut segments = Vec::new();
+    let mut position = 2;
+    loop {
+        // Markers may be preceded by any number of fill bytes
+        while input.get(position) == Some(&0xff) && input.get(position + 1) == Some(&0xff) {
+            position += 1;
+        }
+        let (Some(0xff), Some(&marker)) = (input.get(position), input.get(position + 1)) else {
+            return Err(malformed());
+        };
+        position += 2;
+        if marker == 0xd9 {
+            return Ok(segments);
+        }
+        let length = input
+            .get(position..position + 2)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .filter(|&length| length >= 2)
+            .ok_or_else(malformed)?;
+        let payload = input
+            .get(position + 2..position + length)
+            .ok_or_else(malformed)?;
+        position += length;
+        let mut entropy: &[u8] = &[];
+        if marker == 0xda {
+            // Entropy-coded data runs up to the next marker other than a stuffed zero or a restart
+            let start = position;
+            while position + 1 < input.len()
+                && !(input[position] == 0xff
+                    && input[position + 1] != 0
+                    && !(0xd0..=0xd7).contains(&input[position + 1]))
+            {
+                position += 1;
+            }
+            if position + 1 >= input.len() {
+                position = input.len();
+            }
+            entropy = &input[start..position];
+        }
+        segments.push(Segment {
+            marker,
+            payload,
+            entropy,
+        });
+        if position >= input.len() {
+            // Truncated files end without an end of image marker
+            return Ok(segments);
+        }
+    }
+}
+
+/// Losslessly shrinks a JPEG file jpegtran-style: the DCT coefficients are kept while the
+/// Huffman tables are optimized, the scans optionally made progressive and the metadata the
+/// policy excludes, comments and unknown application segments are dropped. Files which are
+/// not baseline or extended sequential Huffman coded 8-bit JPEGs only have their segments
+/// stripped.
+pub(crate) fn optimize_jpeg(
+    input: &[u8],
+    progressive: bool,
+    policy: &MetadataPolicy,
+    limits: &Limits,
+) -> Result<Vec<u8>> {
+    let segments = split_segments(input)?;
+    let mut output = vec![0xff, 0xd8];
+    write_application_segments(&mut output, &segments, policy)?;
+
+    let frame = segments.iter().find(|segment| {
+        (0xc0..=0xcf).contains(&segment.marker) && ![0xc4, 0xc8, 0xcc].contains(&segment.marker)
+    });
+    let sequential = frame.is_some_and(|frame| {
+        (frame.marker == 0xc0 || frame.marker == 0xc1) && frame.payload.first() == Some(&8)
+    });
+    match frame {
+        Some(frame) if sequential => {
+            let mut frame = Frame::parse(frame.payload, limits)?;
+            decode_scans(&mut frame, &segments)?;
+            for segment in segments.iter().filter(|segment| segment.marker == 0xdb) {
+                write_segment(&mut output, 0xdb, segment.payload);
+            }
+            write_frame(&mut output, &frame, progressive);
+        }
+        _ => {
+            for segment in segments
+                .iter()
+                .filter(|segment| !is_metadata(segment.marker))
+            {
+                write_segment(&mut output, segment.marker, segment.payload);
+                output.extend_from_slice(segment.entropy);
+            }
+        }
+    }
+    output.extend_from_slice(&[0xff, 0xd9]);
+    Ok(output)
+}
+
+/// Payloads of the APP1 segments of a JPEG file, none if its structure is malformed
+pub(crate) fn app1_payloads(input: &[u8]) -> Vec<&[u8]> {
+    split_segments(input)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|segment| segment.marker == 0xe1)
+        .map(|segment| segment.payload)
+        .collect()
+}
+
+/// Whether the marker starts an application or comment segment
+fn is_metadata(marker: u8) -> bool {
+    (0xe0..=0xef).contains(&marker) || marker == 0xfe
+}
+
+/// Writes the JFIF header, the metadata the policy keeps, the ICC profile and the Adobe color
+/// transform, dropping comments and all other application segments
+fn write_application_segments(
+    output: &mut Vec<u8>,
+    segments: &[Segment],
+    policy: &MetadataPolicy,
+) -> Result<()> {
+    let application = |marker: u8, signature: &'static [u8]| {
+        segments
+            .iter()
+            .filter(move |segment| {
+                segment.marker == marker && segment.payload.starts_with(signature)
+            })
+            .map(|segment| segment.payload)
+    };
+    let metadata = Metadata {
+        icc_profile: None,
+        exif: application(0xe1, b"Exif\0\0")
+            .next()
+            .map(|exif| exif[6..].to_vec()),
+        xmp: application(0xe1, b"http://ns.adobe.com/xap/1.0/\0")
+            .next()
+            .map(|xmp| metadata::merge_extended_xmp(xmp[29..].to_vec(), application(0xe1, b""))),
+        iptc: application(0xed, b"Photoshop 3.0\0")
+            .next()
+            .map(|iptc| iptc[14..].to_vec())
+            .filter(|iptc| iptc.starts_with(b"8BIM")),
+        // The pixels cannot be rotated losslessly, so the orientation tag is left alone
+        orientation: Orientation::NoTransforms,
+    };
+    let metadata = policy.apply(metadata);
+
+    for jfif in application(0xe0, b"JFIF\0").take(1) {
+        write_segment(output, 0xe0, jfif);
+    }
+    for (number, segment) in metadata::jpeg_app_segments(&metadata)? {
+        write_segment(output, 0xe0 + number, &segment);
+    }
+    for icc_profile in application(0xe2, b"ICC_PROFILE\0") {
+        write_segment(output, 0xe2, icc_profile);
+    }
+    for adobe in application(0xee, b"Adobe").take(1) {
+        write_segment(output, 0xee, adobe);
+    }
+    Ok(())
+}
+
+fn write_segment(output: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    output.extend_from_slice(&[0xff, marker]);
+    output.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+/// Coefficients of a block in zigzag order
+type Block = [i16; 64];
+
+/// Color component of a frame with its quantized DCT coefficients
+struct Component {
+    id: u8,
+    horizontal: usize,
+    vertical: usize,
+    /// Blocks per line covering whole MCUs
+    padded_width: usize,
+    /// Blocks covering the component, without the MCU padding
+    width: usize,
+    height: usize,
+    blocks: Vec<Block>,
+}
+
+/// Frame header with the decoded coefficients of all components
+struct Frame<'a> {
+    header: &'a [u8],
+    mcus_per_line: usize,
+    mcu_lines: usize,
+    components: Vec<Component>,
+}
+
+impl<'a> Frame<'a> {
+    /// Parses the header and allocates the coefficients of every block, checked against
+    /// the allocation limit first
+    fn parse(header: &'a [u8], limits: &Limits) -> Result<Self> {
+        let malformed = || ImageProcessorError::malformed("Malformed JPEG frame header");
+        let [_, height_high, height_low, width_high, width_low, count, ..] = *header else {
+            return Err(malformed());
+        };
+        let height = u16::from_be_bytes([height_high, height_low]) as usize;
+        let width = u16::from_be_bytes([width_high, width_low]) as usize;
+        let specs = header
+            .get(6..6 + count as usize * 3)
+            .filter(|specs| !specs.is_empty())
+            .ok_or_else(malformed)?;
+        if width == 0 || height == 0 {
+            return Err(malformed());
+        }
+        let sampling = |spec: &[u8]| ((spec[1] >> 4) as usize, (spec[1] & 0x0f) as usize);
+        if specs.chunks(3).any(|spec| {
+            !(1..=4).contains(&sampling(spec).0) || !(1..=4).contains(&sampling(spec).1)
+        }) {
+            return Err(malformed());
+        }
+        let max_horizontal = specs
+            .chunks(3)
+            .map(|spec| sampling(spec).0)
+            .max()
+            .unwrap_or(1);
+        let max_vertical = specs
+            .chunks(3)
+            .map(|spec| sampling(spec).1)
+            .max()
+            .unwrap_or(1);
+        let mcus_per_line = width.div_ceil(8 * max_horizontal);
+        let mcu_lines = height.div_ceil(8 * max_vertical);
+        let blocks: usize = specs
+            .chunks(3)
+            .map(|spec| mcus_per_line * sampling(spec).0 * mcu_lines * sampling(spec).1)
+            .sum();
+        limits.check_alloc((blocks * size_of::<Block>()) as u64, "DCT coefficients")?;
+        let components = specs
+            .chunks(3)
+            .map(|spec| {
+                let (horizontal, vertical) = sampling(spec);
+                let padded_width = mcus_per_line * horizontal;
+                Component {
+                    id: spec[0],
+                    horizontal,
+                    vertical,
+                    padded_width,
+                    width: (width * horizontal).div_ceil(max_horizontal).div_ceil(8),
+                    height: (height * vertical).div_ceil(max_vertical).div_ceil(8),
+                    blocks: vec![[0; 64]; padded_width * mcu_lines * vertical],
+                }
+            })
+            .collect();
+        Ok(Frame {
+            header,
+            mcus_per_line,
+            mcu_lines,
+            components,
+        })
+    }
+
+    /// MCU number, component and block index of every block of a scan in coding order. Scans
+    /// of a single component only cover its blocks without padding. The iterator does not
+    /// borrow the frame, so the blocks can be written while iterating.
+    fn coding_order(
+        &self,
+        components: &[usize],
+    ) -> impl Iterator<Item = (usize, usize, usize)> + use<> {
+        let (mcus_per_line, mcu_lines, layout): (_, _, Rc<[_]>) = match *components {
+            [c] => {
+                let component = &self.components[c];
+                let layout = [(c, 1, 1, component.padded_width)];
+                (component.width, component.height, layout.into())
+            }
+            _ => {
+                let layout = components.iter().map(|&c| {
+                    let component = &self.components[c];
+                    let (horizontal, vertical) = (component.horizontal, component.vertical);
+                    (c, horizontal, vertical, component.padded_width)
+                });
+                (self.mcus_per_line, self.mcu_lines, layout.collect())
+            }
+        };
+        (0..mcus_per_line * mcu_lines).flat_map(move |mcu| {
+            let (mcu_y, mcu_x) = (mcu / mcus_per_line, mcu % mcus_per_line);
+            let layout = layout.clone();
+            (0..layout.len()).flat_map(move |i| {
+                let (c, horizontal, vertical, padded_width) = layout[i];
+                (0..vertical * horizontal).map(move |block| {
+                    let y = mcu_y * vertical + block / horizontal;
+                    let x = mcu_x * horizontal + block % horizontal;
+                    (mcu, c, y * padded_width + x)
+                })
+            })
+        })
+    }
+}
+
+/// Huffman table used for decoding, in the canonical form of the JPEG specification
+#[derive(Clone, Default)]
+struct DecodingTable {
+    /// Largest code of each length, -1 if there is none
+    max_code: [i32; 17],
+    /// Offset from the codes of each length to their index into the values
+    offsets: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl DecodingTable {
+    fn parse(counts: &[u8], values: &[u8]) -> Self {
+        let mut table = DecodingTable {
+            max_code: [-1; 17],
+            offsets: [0; 17],
+            values: values.to_vec(),
+        };
+        let mut code = 0;
+        let mut index = 0;
+        for length in 1..=16 {
+            let count = counts[length - 1] as i32;
+            if count > 0 {
+                table.offsets[length] = index - code;
+                code += count;
+                index += count;
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+}
+
+/// Reads the bits of entropy-coded data, removing the stuffed zeros
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.bits == 0 {
+            // Markers and the end of the data read as zeros
+            let byte = match self.data.get(self.position) {
+                Some(0xff) if self.data.get(self.position + 1) == Some(&0) => {
+                    self.position += 2;
+                    0xff
+                }
+                Some(0xff) | None => 0,
+                Some(&byte) => {
+                    self.position += 1;
+                    byte
+                }
+            };
+            self.buffer = byte as u32;
+            self.bits = 8;
+        }
+        self.bits -= 1;
+        (self.buffer >> self.bits) & 1
+    }
+
+    fn bits(&mut self, count: u8) -> u32 {
+        (0..count).fold(0, |value, _| (value << 1) | self.bit())
+    }
+
+    /// Reads a magnitude category and the value it is followed by
+    fn value(&mut self, size: u8) -> i16 {
+        if size == 0 {
+            return 0;
+        }
+        let bits = self.bits(size) as i32;
+        if bits < 1 << (size - 1) {
+            (bits - (1 << size) + 1) as i16
+        } else {
+            bits as i16
+        }
+    }
+
+    fn symbol(&mut self, table: &DecodingTable) -> Result<u8> {
+        let mut code = 0;
+        for length in 1..=16 {
+            code = (code << 1) | self.bit() as i32;
+            if code <= table.max_code[length] {
+                return table
+                    .values
+                    .get((table.offsets[length] + code) as usize)
+                    .copied()
+                    .ok_or_else(|| ImageProcessorError::malformed("Invalid Huffman code"));
+            }
+        }
+        Err(ImageProcessorError::malformed("Invalid Huffman code"))
+    }
+
+    /// Skips to the data following the next restart marker
+    fn restart(&mut self) {
+        self.bits = 0;
+        while self.position + 1 < self.data.len()
+            && !(self.data[self.position] == 0xff
+                && (0xd0..=0xd7).contains(&self.data[self.position + 1]))
+        {
+            self.position += 1;
+        }
+        self.position += 2;
+    }
+}
+
+/// Decodes the coefficients of all scans of a sequential Huffman coded file
+fn decode_scans(frame: &mut Frame, segments: &[Segment]) -> Result<()> {
+    let malformed = |what: &str| ImageProcessorError::malformed(format!("Malformed JPEG {}", what));
+    let mut dc_tables = vec![DecodingTable::default(); 4];
+    let mut ac_tables = vec![DecodingTable::default(); 4];
+    let mut restart_interval = 0;
+    for segment in segments {
+        match segment.marker {
+            0xc4 => {
+                let mut rest = segment.payload;
+                while let [class_id, ref tail @ ..] = *rest {
+                    let counts = tail.get(..16).ok_or_else(|| malformed("Huffman table"))?;
+                    let count = counts.iter().map(|&c| c as usize).sum::<usize>();
+                    let values = tail
+                        .get(16..16 + count)
+                        .ok_or_else(|| malformed("Huffman table"))?;
+                    let table = DecodingTable::parse(counts, values);
+                    let id = (class_id & 0x03) as usize;
+                    if class_id >> 4 == 0 {
+                        dc_tables[id] = table;
+                    } else {
+                        ac_tables[id] = table;
+                    }
+                    rest = &tail[16 + count..];
+                }
+            }
+            0xdd => {
+                let [high, low, ..] = *segment.payload else {
+                    return Err(malformed("restart interval"));
+                };
+                restart_interval = u16::from_be_bytes([high, low]) as usize;
+            }
+            0xda => {
+                let count = *segment
+                    .payload
+                    .first()
+                    .ok_or_else(|| malformed("scan header"))? as usize;
+                let specs = segment
+                    .payload
+                    .get(1..1 + count * 2)
+                    .ok_or_else(|| malformed("scan header"))?;
+                let mut components = Vec::with_capacity(count);
+                let mut tables = Vec::with_capacity(count);
+                for spec in specs.chunks(2) {
+                    let c = frame
+                        .components
+                        .iter()
+                        .position(|component| component.id == spec[0])
+                        .ok_or_else(|| malformed("scan header"))?;
+                    components.push(c);
+                    tables.push(((spec[1] >> 4) as usize & 3, (spec[1] & 0x0f) as usize & 3));
+                }
+                let mut reader = BitReader::new(segment.entropy);
+                let mut predictions = vec![0i16; frame.components.len()];
+                let mut current_mcu = 0;
+                for (mcu, c, index) in frame.coding_order(&components) {
+                    if mcu != current_mcu {
+                        current_mcu = mcu;
+                        if restart_interval > 0 && mcu % restart_interval == 0 {
+                            reader.restart();
+                            predictions.iter_mut().for_each(|p| *p = 0);
+                        }
+                    }
+                    let (dc, ac) = tables[components.iter().position(|&s| s == c).unwrap_or(0)];
+                    let block = &mut frame.components[c].blocks[index];
+                    *block = [0; 64];
+                    let size = reader.symbol(&dc_tables[dc])?;
+                    predictions[c] = predictions[c].wrapping_add(reader.value(size));
+                    block[0] = predictions[c];
+                    let mut k = 1;
+                    while k < 64 {
+                        let symbol = reader.symbol(&ac_tables[ac])?;
+                        let (run, size) = ((symbol >> 4) as usize, symbol & 0x0f);
+                        if size == 0 {
+                            if run != 15 {
+                                break;
+                            }
+                            k += 16;
+                            continue;
+                        }
+                        k += run;
+                        if k > 63 {
+                            return Err(malformed("coefficients"));
+                        }
+                        block[k] = reader.value(size);
+                        k += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Scan of the output, the successive approximation is never used
+struct Scan {
+    components: Vec<usize>,
+    start: usize,
+    end: usize,
+}
+
+/// Largest number of blocks in an MCU of an interleaved scan
+const MAX_BLOCKS_PER_MCU: usize = 10;
+
+/// Scans the coefficients are written in, spectral selection only when progressive
+fn scan_script(frame: &Frame, progressive: bool) -> Vec<Scan> {
+    let all: Vec<usize> = (0..frame.components.len()).collect();
+    let interleavable = all.len() <= 4
+        && frame
+            .components
+            .iter()
+            .map(|component| component.horizontal * component.vertical)
+            .sum::<usize>()
+            <= MAX_BLOCKS_PER_MCU;
+    let grouped = |start, end| -> Vec<Scan> {
+        if interleavable {
+            vec![Scan {
+                components: all.clone(),
+                start,
+                end,
+            }]
+        } else {
+            all.iter()
+                .map(|&c| Scan {
+                    components: vec![c],
+                    start,
+                    end,
+                })
+                .collect()
+        }
+    };
+    if !progressive {
+        return grouped(0, 63);
+    }
+    let mut scans = grouped(0, 0);
+    for &c in &all {
+        scans.push(Scan {
+            components: vec![c],
+            start: 1,
+            end: 5,
+        });
+        scans.push(Scan {
+            components: vec![c],
+            start: 6,
+            end: 63,
+        });
+    }
+    scans
+}
+
+/// Receives the symbols and raw bits of a scan
+trait EntropySink {
+    fn symbol(&mut self, table: usize, symbol: u8);
+    fn bits(&mut self, value: u32, count: u8);
+}
+
+/// Counts the symbols for building the optimal Huffman tables
+struct SymbolCounter {
+    frequencies: [[u32; 257]; 4],
+}
+
+impl EntropySink for SymbolCounter {
+    fn symbol(&mut self, table: usize, symbol: u8) {
+        self.frequencies[table][symbol as usize] += 1;
+    }
+
+    fn bits(&mut self, _value: u32, _count: u8) {}
+}
+
+/// Writes the Huffman coded symbols, stuffing zeros after 0xFF bytes
+struct BitWriter {
+    codes: [[(u16, u8); 256]; 4],
+    output: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl EntropySink for BitWriter {
+    fn symbol(&mut self, table: usize, symbol: u8) {
+        let (code, length) = self.codes[table][symbol as usize];
+        self.bits(code as u32, length);
+    }
+
+    fn bits(&mut self, value: u32, count: u8) {
+        for i in (0..count).rev() {
+            self.buffer = (self.buffer << 1) | ((value >> i) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.output.push(self.buffer as u8);
+                if self.buffer as u8 == 0xff {
+                    self.output.push(0);
+                }
+                self.buffer = 0;
+                self.bits = 0;
+            }
+        }
+    }
+}
+
+impl BitWriter {
+    /// Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.bits((1 << padding) - 1, padding);
+        }
+        self.output
+    }
+}
+
+/// Table slots of the output: DC and AC tables for luma and for chroma
+const DC_TABLES: [usize; 2] = [0, 1];
+const AC_TABLES: [usize; 2] = [2, 3];
+
+/// Luma uses the first table of each class, all other components the second
+fn table_id(c: usize) -> usize {
+    c.min(1)
+}
+
+/// Magnitude category of a coefficient and its bits
+fn magnitude(value: i16) -> (u8, u32) {
+    let size = (16 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value } as u32 & ((1 << size) - 1);
+    (size, bits)
+}
+
+/// Feeds the symbols of a scan into the sink
+fn encode_scan(frame: &Frame, scan: &Scan, sink: &mut impl EntropySink) {
+    let mut predictions = vec![0i16; frame.components.len()];
+    let mut eob_run = 0u32;
+    let flush_eob_run = |sink: &mut dyn FnMut(u8, u32, u8), eob_run: &mut u32| {
+        if *eob_run > 0 {
+            let size = (31 - eob_run.leading_zeros()) as u8;
+            sink(size << 4, *eob_run - (1 << size), size);
+            *eob_run = 0;
+        }
+    };
+    for (_, c, index) in frame.coding_order(&scan.components) {
+        let block = &frame.components[c].blocks[index];
+        let ac_table = AC_TABLES[table_id(c)];
+        if scan.start == 0 {
+            let (size, bits) = magnitude(block[0].wrapping_sub(predictions[c]));
+            predictions[c] = block[0];
+            sink.symbol(DC_TABLES[table_id(c)], size);
+            sink.bits(bits, size);
+        }
+        let first = scan.start.max(1);
+        if scan.end < first {
+            continue;
+        }
+        let mut run = 0;
+        for &value in &block[first..=scan.end] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            flush_eob_run(
+                &mut |symbol, bits, count| {
+                    sink.symbol(ac_table, symbol);
+                    sink.bits(bits, count);
+                },
+                &mut eob_run,
+            );
+            while run >= 16 {
+                sink.symbol(ac_table, 0xf0);
+                run -= 16;
+            }
+            let (size, bits) = magnitude(value);
+            sink.symbol(ac_table, ((run << 4) as u8) | size);
+            sink.bits(bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            if scan.start == 0 {
+                sink.symbol(ac_table, 0x00);
+            } else {
+                // Progressive scans merge the ends of consecutive blocks into runs
+                eob_run += 1;
+                if eob_run == 0x7fff {
+                    flush_eob_run(
+                        &mut |symbol, bits, count| {
+                            sink.symbol(ac_table, symbol);
+                            sink.bits(bits, count);
+                        },
+                        &mut eob_run,
+                    );
+                }
+            }
+        }
+    }
+    if let Some(&c) = scan.components.first() {
+        let ac_table = AC_TABLES[table_id(c)];
+        flush_eob_run(
+            &mut |symbol, bits, count| {
+                sink.symbol(ac_table, symbol);
+                sink.bits(bits, count);
+            },
+            &mut eob_run,
+        );
+    }
+}
+
+/// Builds the optimal Huffman table for the symbol frequencies, limited to codes of 16 bits,
+/// following annex K.2 of the JPEG specification. Returns the number of codes of each length
+/// and the symbols ordered by code length.
+fn optimal_table(frequencies: &[u32; 257]) -> ([u8; 16], Vec<u8>) {
+    let mut frequencies = *frequencies;
+    // Reserving a symbol keeps any code from consisting of ones only
+    frequencies[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [usize::MAX; 257];
+    loop {
+        // The two least frequent symbols, preferring the larger symbol on ties
+        let mut least = None;
+        let mut second = None;
+        for symbol in 0..257 {
+            if frequencies[symbol] == 0 {
+                continue;
+            }
+            match least {
+                Some(l) if frequencies[symbol] > frequencies[l] => {
+                    if second.is_none_or(|s| frequencies[symbol] <= frequencies[s]) {
+                        second = Some(symbol);
+                    }
+                }
+                _ => {
+                    second = least;
+                    least = Some(symbol);
+                }
+            }
+        }
+        let (Some(mut v1), Some(mut v2)) = (least, second) else {
+            break;
+        };
+        frequencies[v1] += frequencies[v2];
+        frequencies[v2] = 0;
+        code_size[v1] += 1;
+        while others[v1] != usize::MAX {
+            v1 = others[v1];
+            code_size[v1] += 1;
+        }
+        others[v1] = v2;
+        code_size[v2] += 1;
+        while others[v2] != usize::MAX {
+            v2 = others[v2];
+            code_size[v2] += 1;
+        }
+    }
+
+    let mut counts = [0u32; 33];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        counts[size.min(32)] += 1;
+    }
+    // Shorten the codes longer than 16 bits by borrowing from shorter ones
+    for length in (17..=32).rev() {
+        while counts[length] > 0 {
+            let mut j = length - 2;
+            while counts[j] == 0 {
+                j -= 1;
+            }
+            counts[length] -= 2;
+            counts[length - 1] += 1;
+            counts[j + 1] += 2;
+            counts[j] -= 1;
+        }
+    }
+    // Remove the reserved symbol, which has the longest code
+    if let Some(length) = (1..=16).rev().find(|&length| counts[length] > 0) {
+        counts[length] -= 1;
+    }
+
+    let mut symbols: Vec<usize> = (0..256).filter(|&symbol| code_size[symbol] > 0).collect();
+    symbols.sort_by_key(|&symbol| code_size[symbol]);
+    let mut lengths = [0; 16];
+    for (length, count) in lengths.iter_mut().zip(&counts[1..=16]) {
+        *length = *count as u8;
+    }
+    (
+        lengths,
+        symbols.into_iter().map(|symbol| symbol as u8).collect(),
+    )
+}
+
+/// Canonical codes of the symbols of a Huffman table
+fn table_codes(lengths: &[u8; 16], symbols: &[u8]) -> [(u16, u8); 256] {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0u16;
+    let mut symbols = symbols.iter();
+    for (length, &count) in lengths.iter().enumerate() {
+        for symbol in symbols.by_ref().take(count as usize) {
+            codes[*symbol as usize] = (code, length as u8 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// Writes the frame header and the scans with their optimal Huffman tables
+fn write_frame(output: &mut Vec<u8>, frame: &Frame, progressive: bool) {
+    write_segment(output, if progressive { 0xc2 } else { 0xc0 }, frame.header);
+    for scan in scan_script(frame, progressive) {
+        let mut counter = SymbolCounter {
+            frequencies: [[0; 257]; 4],
+        };
+        encode_scan(frame, &scan, &mut counter);
+
+        let mut tables = Vec::new();
+        let mut codes = [[(0, 0); 256]; 4];
+        for (slot, frequencies) in counter.frequencies.iter().enumerate() {
+            if frequencies.iter().all(|&frequency| frequency == 0) {
+                continue;
+            }
+            let (lengths, symbols) = optimal_table(frequencies);
+            codes[slot] = table_codes(&lengths, &symbols);
+            // Class and id of the table
+            tables.push((((slot / 2) << 4) | (slot % 2)) as u8);
+            tables.extend_from_slice(&lengths);
+            tables.extend_from_slice(&symbols);
+        }
+        write_segment(output, 0xc4, &tables);
+
+        let mut header = vec![scan.components.len() as u8];
+        for &c in &scan.components {
+            let id = table_id(c) as u8;
+            header.extend_from_slice(&[frame.components[c].id, (id << 4) | id]);
+        }
+        header.extend_from_slice(&[scan.start as u8, scan.end as u8, 0]);
+        write_segment(output, 0xda, &header);
+
+        let mut writer = BitWriter {
+            codes,
+            output: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        };
+        encode_scan(frame, &scan, &mut writer);
+        output.extend_from_slice(&writer.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    fn decode(jpeg: &[u8]) -> image::RgbImage {
+        image::load_from_memory(jpeg).unwrap().to_rgb8()
+    }
+
+    #[test]
+    fn test_optimize_jpeg() {
+        let input = std::fs::read("test.jpg").unwrap();
+        let pixels = decode(&input);
+        for progressive in [false, true] {
+            let optimized = optimize_jpeg(
+                &input,
+                progressive,
+                &MetadataPolicy::KeepAll,
+                &Limits::default(),
+            )
+            .unwrap();
+            if !progressive {
+                // Progressive scans need more headers than they save on such a small image
+                assert!(optimized.len() < input.len());
+            }
+            assert_eq!(decode(&optimized), pixels);
+            let sof = if progressive {
+                [0xff, 0xc2]
+            } else {
+                [0xff, 0xc0]
+            };
+            assert!(optimized.windows(2).any(|w| w == sof));
+        }
+
+        let stripped =
+            optimize_jpeg(&input, false, &MetadataPolicy::StripAll, &Limits::default()).unwrap();
+        assert!(!stripped.windows(4).any(|w| w == b"Exif"));
+        assert_eq!(decode(&stripped), pixels);
+    }
+
+    #[test]
+    fn test_optimize_subsampled_jpeg() {
+        // Odd dimensions leave partial MCUs, grayscale exercises single component scans
+        let image = image::RgbImage::from_fn(37, 21, |x, y| {
+            image::Rgb([(x * 7) as u8, (y * 12) as u8, ((x + y) * 5) as u8])
+        });
+        for (color_type, pixels) in [
+            (jpeg_encoder::ColorType::Rgb, image.as_raw().clone()),
+            (
+                jpeg_encoder::ColorType::Luma,
+                image::DynamicImage::ImageRgb8(image.clone())
+                    .to_luma8()
+                    .into_raw(),
+            ),
+        ] {
+            for sampling in [
+                jpeg_encoder::SamplingFactor::F_2_2,
+                jpeg_encoder::SamplingFactor::F_2_1,
+            ] {
+                let mut input = Vec::new();
+                let mut encoder = jpeg_encoder::Encoder::new(&mut input, 85);
+                encoder.set_sampling_factor(sampling);
+                encoder.set_restart_interval(2);
+                encoder.encode(&pixels, 37, 21, color_type).unwrap();
+                for progressive in [false, true] {
+                    let optimized = optimize_jpeg(
+                        &input,
+                        progressive,
+                        &MetadataPolicy::KeepAll,
+                        &Limits::default(),
+                    )
+                    .unwrap();
+                    assert_eq!(decode(&optimized), decode(&input));
+                    assert_eq!(
+                        image::load_from_memory(&optimized).unwrap().dimensions(),
+                        (37, 21)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_progressive_input() {
+        // Progressive files keep their scans and only have their segments stripped
+        let image = image::RgbImage::from_pixel(16, 16, image::Rgb([200, 30, 90]));
+        let mut input = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut input, 85);
+        encoder.set_progressive(true);
+        encoder.add_app_segment(15, b"junk".to_vec()).unwrap();
+        encoder
+            .encode(image.as_raw(), 16, 16, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        let optimized =
+            optimize_jpeg(&input, false, &MetadataPolicy::KeepAll, &Limits::default()).unwrap();
+        assert_eq!(optimized.len(), input.len() - 8);
+        assert_eq!(decode(&optimized), decode(&input));
+    }
+
+    #[test]
+    fn test_optimal_table() {
+        let mut frequencies = [0; 257];
+        frequencies[0] = 50;
+        frequencies[1] = 30;
+        frequencies[2] = 15;
+        frequencies[3] = 5;
+        let (lengths, symbols) = optimal_table(&frequencies);
+        assert_eq!(symbols, [0, 1, 2, 3]);
+        assert_eq!(&lengths[..4], &[1, 1, 1, 1]);
+        let codes = table_codes(&lengths, &symbols);
+        assert_eq!(codes[0], (0b0, 1));
+        assert_eq!(codes[3], (0b1110, 4));
+
+        // Skewed frequencies still fit into 16 bits
+        let mut frequencies = [0; 257];
+        for (symbol, frequency) in frequencies.iter_mut().take(40).enumerate() {
+            *frequency = 1 << (symbol / 2).min(30);
+        }
+        let (lengths, symbols) = optimal_table(&frequencies);
+        assert_eq!(lengths.iter().map(|&l| l as usize).sum::<usize>(), 40);
+        assert_eq!(symbols.len(), 40);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{ImageProcessorError, OutputFormat, Result, color};
+use image::{ImageDecoder, metadata::Orientation};
+use md5::{Digest, Md5};
+
+/// Metadata carried over from the decoded input into the encoded output
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Metadata {
+    /// ICC color profile, sRGB profiles are left out as untagged images are sRGB already
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF block as a TIFF structure, without the `Exif\0\0` prefix of JPEG APP1 segments.
+    /// Its orientation tag is reset, the orientation has to be applied to the pixels instead.
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// Photoshop image resources holding the IPTC datasets, as found in JPEG APP13 segments
+    pub iptc: Option<Vec<u8>>,
+    /// Orientation the EXIF block asked viewers to display the image in
+    pub orientation: Orientation,
+}
+
+impl Metadata {
+    /// Reads the metadata the decoder exposes before the pixels are decoded
+    pub fn read(decoder: &mut impl ImageDecoder) -> Result<Self> {
+        let icc_profile = decoder
+            .icc_profile()
+            .map_err(|e| ImageProcessorError::from_image("Failed to read ICC profile", e))?;
+        let mut exif = decoder
+            .exif_metadata()
+            .map_err(|e| ImageProcessorError::from_image("Failed to read EXIF metadata", e))?;
+        let xmp = decoder
+            .xmp_metadata()
+            .map_err(|e| ImageProcessorError::from_image("Failed to read XMP metadata", e))?;
+        // Only JPEG stores IPTC as image resources, PNG text chunks encode them differently
+        let iptc = decoder
+            .iptc_metadata()
+            .map_err(|e| ImageProcessorError::from_image("Failed to read IPTC metadata", e))?
+            .filter(|iptc| iptc.starts_with(b"8BIM"));
+        let orientation = exif
+            .as_mut()
+            .and_then(|exif| Orientation::remove_from_exif_chunk(exif))
+            .unwrap_or(Orientation::NoTransforms);
+        Ok(Metadata {
+            icc_profile: icc_profile.filter(|icc_profile| !color::is_srgb(icc_profile)),
+            exif,
+            xmp,
+            iptc,
+            orientation,
+        })
+    }
+}
+
+/// VP8X flag announcing an ICCP chunk
+const WEBP_ICC_FLAG: u8 = 0x20;
+/// VP8X flag announcing an alpha channel
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+/// VP8X flag announcing an EXIF chunk
+const WEBP_EXIF_FLAG: u8 = 0x08;
+/// VP8X flag announcing an XMP chunk
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Rewrites a still WebP image into the extended format carrying the metadata chunks
+pub(crate) fn embed_in_webp(
+    encoded: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    if metadata.icc_profile.is_none() && metadata.exif.is_none() && metadata.xmp.is_none() {
+        return Ok(encoded.to_vec());
+    }
+    if encoded.len() < 12 || &encoded[0..4] != b"RIFF" || &encoded[8..12] != b"WEBP" {
+        return Err(ImageProcessorError::encode(
+            OutputFormat::WebP,
+            "Encoded WebP image has no RIFF header",
+            None,
+        ));
+    }
+
+    let mut flags = if has_alpha { WEBP_ALPHA_FLAG } else { 0 };
+    let mut image_chunks = Vec::new();
+    let mut rest = &encoded[12..];
+    while rest.len() >= 8 {
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let end = (8 + size + size % 2).min(rest.len());
+        match &rest[0..4] {
+            b"VP8X" if size >= 1 && rest.len() > 8 => flags |= rest[8] & WEBP_ALPHA_FLAG,
+            _ => image_chunks.extend_from_slice(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+
+    let mut vp8x = vec![0; 10];
+    if metadata.icc_profile.is_some() {
+        flags |= WEBP_ICC_FLAG;
+    }
+    if metadata.exif.is_some() {
+        flags |= WEBP_EXIF_FLAG;
+    }
+    if metadata.xmp.is_some() {
+        flags |= WEBP_XMP_FLAG;
+    }
+    vp8x[0] = flags;
+    // The canvas size is stored minus one in 24 bits
+    let canvas_size = |size: u32| {
+        size.checked_sub(1)
+            .filter(|size| *size < 1 << 24)
+            .ok_or_else(|| {
+                ImageProcessorError::encode(OutputFormat::WebP, "Invalid WebP canvas size", None)
+            })
+    };
+    vp8x[4..7].copy_from_slice(&canvas_size(width)?.to_le_bytes()[..3]);
+    vp8x[7..10].copy_from_slice(&canvas_size(height)?.to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    push_riff_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc_profile) = &metadata.icc_profile {
+        push_riff_chunk(&mut body, b"ICCP", icc_profile);
+    }
+    body.extend_from_slice(&image_chunks);
+    if let Some(exif) = &metadata.exif {
+        push_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend_from_slice(&body);
+    Ok(output)
+}
+
+fn push_riff_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    output.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// Signature of JPEG APP1 segments holding EXIF
+const JPEG_EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+/// Signature of JPEG APP1 segments holding XMP
+const JPEG_XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Signature of JPEG APP1 segments holding a part of the extended XMP
+const JPEG_EXTENDED_XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+/// Signature of JPEG APP13 segments holding Photoshop image resources
+const JPEG_IPTC_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+/// Largest payload of a JPEG marker segment
+const JPEG_MAX_SEGMENT: usize = 65533;
+/// Length of the hexadecimal MD5 digest identifying the extended XMP
+const XMP_GUID_LEN: usize = 32;
+/// Property of the main XMP packet naming the extended XMP
+const XMP_HAS_EXTENDED: &str = "xmpNote:HasExtendedXMP";
+
+/// Numbers and payloads of the JPEG APPn segments carrying the EXIF, XMP and IPTC metadata.
+/// XMP too large for a single segment is written as extended XMP, a main packet pointing to
+/// the full packet split across further segments. EXIF and IPTC cannot be split.
+pub(crate) fn jpeg_app_segments(metadata: &Metadata) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut segments = Vec::new();
+    let mut push = |number, signature: &[u8], payload: &[u8]| {
+        let mut segment = Vec::with_capacity(signature.len() + payload.len());
+        segment.extend_from_slice(signature);
+        segment.extend_from_slice(payload);
+        segments.push((number, segment));
+    };
+    let too_large = |what| {
+        ImageProcessorError::encode(
+            OutputFormat::Jpeg,
+            format!("{} metadata too large for a JPEG segment", what),
+            None,
+        )
+    };
+    if let Some(exif) = &metadata.exif {
+        if JPEG_EXIF_SIGNATURE.len() + exif.len() > JPEG_MAX_SEGMENT {
+            return Err(too_large("EXIF"));
+        }
+        push(1, JPEG_EXIF_SIGNATURE, exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        if JPEG_XMP_SIGNATURE.len() + xmp.len() <= JPEG_MAX_SEGMENT {
+            push(1, JPEG_XMP_SIGNATURE, xmp);
+        } else {
+            let full_len = u32::try_from(xmp.len()).map_err(|_| too_large("XMP"))?;
+            let guid = format!("{:X}", Md5::digest(xmp));
+            let main = format!(
+                concat!(
+                    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                    "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                    "<rdf:Description rdf:about=\"\" ",
+                    "xmlns:xmpNote=\"http://ns.adobe.com/xmp/note/\" {}=\"{}\"/>",
+                    "</rdf:RDF></x:xmpmeta>"
+                ),
+                XMP_HAS_EXTENDED, guid
+            );
+            push(1, JPEG_XMP_SIGNATURE, main.as_bytes());
+            let header_len = JPEG_EXTENDED_XMP_SIGNATURE.len() + XMP_GUID_LEN + 8;
+            for (index, chunk) in xmp.chunks(JPEG_MAX_SEGMENT - header_len).enumerate() {
+                let offset = (index * (JPEG_MAX_SEGMENT - header_len)) as u32;
+                let mut payload = Vec::with_capacity(header_len + chunk.len());
+                payload.extend_from_slice(guid.as_bytes());
+                payload.extend_from_slice(&full_len.to_be_bytes());
+                payload.extend_from_slice(&offset.to_be_bytes());
+                payload.extend_from_slice(chunk);
+                push(1, JPEG_EXTENDED_XMP_SIGNATURE, &payload);
+            }
+        }
+    }
+    if let Some(iptc) = &metadata.iptc {
+        if JPEG_IPTC_SIGNATURE.len() + iptc.len() > JPEG_MAX_SEGMENT {
+            return Err(too_large("IPTC"));
+        }
+        push(13, JPEG_IPTC_SIGNATURE, iptc);
+    }
+    Ok(segments)
+}
+
+/// Merges the extended XMP found in the JPEG APP1 segments into the main packet, so policies
+/// filter and encoders write all of it. Extended XMP that is incomplete or does not match the
+/// GUID of the main packet is ignored.
+pub(crate) fn merge_extended_xmp<'a>(
+    xmp: Vec<u8>,
+    app1_segments: impl Iterator<Item = &'a [u8]>,
+) -> Vec<u8> {
+    let Some((guid, property)) = extended_xmp_guid(&xmp) else {
+        return xmp;
+    };
+    let mut full_len = None;
+    let mut chunks = Vec::new();
+    for segment in app1_segments {
+        let Some(payload) = segment.strip_prefix(JPEG_EXTENDED_XMP_SIGNATURE) else {
+            continue;
+        };
+        let Some((header, data)) = payload.split_at_checked(XMP_GUID_LEN + 8) else {
+            continue;
+        };
+        if header[..XMP_GUID_LEN] != *guid.as_bytes() {
+            continue;
+        }
+        let len = u32::from_be_bytes(header[XMP_GUID_LEN..XMP_GUID_LEN + 4].try_into().unwrap());
+        let offset = u32::from_be_bytes(header[XMP_GUID_LEN + 4..].try_into().unwrap());
+        if *full_len.get_or_insert(len) != len {
+            return xmp;
+        }
+        chunks.push((offset as usize, data));
+    }
+    // The chunks have to cover the declared length exactly before it is trusted
+    let Some(full_len) = full_len.map(|len| len as usize) else {
+        return xmp;
+    };
+    if chunks.iter().map(|(_, data)| data.len()).sum::<usize>() != full_len
+        || chunks
+            .iter()
+            .any(|(offset, data)| offset + data.len() > full_len)
+    {
+        return xmp;
+    }
+    let mut extension = vec![0; full_len];
+    for (offset, data) in chunks {
+        extension[offset..offset + data.len()].copy_from_slice(data);
+    }
+    let (Ok(main), Ok(extension)) = (std::str::from_utf8(&xmp), String::from_utf8(extension))
+    else {
+        return xmp;
+    };
+    let descriptions = extension
+        .find("<rdf:RDF")
+        .and_then(|start| Some(start + extension[start..].find('>')? + 1))
+        .zip(extension.rfind("</rdf:RDF>"))
+        .filter(|(start, end)| start <= end);
+    let (Some((start, end)), Some(insert_at)) = (descriptions, main.rfind("</rdf:RDF>")) else {
+        return xmp;
+    };
+    let mut merged = String::with_capacity(main.len() + end - start);
+    merged.push_str(&main[..insert_at]);
+    merged.push_str(&extension[start..end]);
+    merged.push_str(&main[insert_at..]);
+    merged.replacen(&property, "", 1).into_bytes()
+}
+
+/// GUID of the extended XMP the main packet points to, with the text declaring it
+fn extended_xmp_guid(xmp: &[u8]) -> Option<(String, String)> {
+    let xmp = std::str::from_utf8(xmp).ok()?;
+    let start = xmp.find(XMP_HAS_EXTENDED)?;
+    let rest = &xmp[start + XMP_HAS_EXTENDED.len()..];
+    // Either an attribute of the description or an element holding the GUID
+    let (value_start, suffix) = match rest.as_bytes().first()? {
+        b'=' => (2, &rest[1..2]),
+        b'>' => (1, ""),
+        _ => return None,
+    };
+    let guid = rest.get(value_start..value_start + XMP_GUID_LEN)?;
+    if !guid.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let property = if suffix.is_empty() {
+        let end = format!("</{}>", XMP_HAS_EXTENDED);
+        let element_start = xmp[..start].rfind('<')?;
+        let element_end = start + rest.find(&end)? + XMP_HAS_EXTENDED.len() + end.len();
+        xmp[element_start..element_end].to_string()
+    } else {
+        let attribute_end = start + XMP_HAS_EXTENDED.len() + value_start + XMP_GUID_LEN + 1;
+        if xmp.get(attribute_end - 1..attribute_end)? != suffix {
+            return None;
+        }
+        xmp[start..attribute_end].to_string()
+    };
+    Some((guid.to_string(), property))
+}
+
+/// Keyword of PNG iTXt chunks holding XMP
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Inserts the XMP chunk the PNG encoder cannot write before the image data
+pub(crate) fn embed_in_png(encoded: Vec<u8>, metadata: &Metadata) -> Vec<u8> {
+    let Some(xmp) = &metadata.xmp else {
+        return encoded;
+    };
+    // Keyword, uncompressed, no language tag nor translated keyword
+    let mut payload = PNG_XMP_KEYWORD.to_vec();
+    payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+    payload.extend_from_slice(xmp);
+
+    // Skip the signature and the chunks preceding the image data
+    let mut position = 8;
+    while let Some(chunk) = encoded.get(position..position + 8) {
+        if &chunk[4..8] == b"IDAT" {
+            break;
+        }
+        position += 12 + u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+    }
+    let position = position.min(encoded.len());
+    let mut output = Vec::with_capacity(encoded.len() + payload.len() + 12);
+    output.extend_from_slice(&encoded[..position]);
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    let crc_start = output.len();
+    output.extend_from_slice(b"iTXt");
+    output.extend_from_slice(&payload);
+    let crc = crc32fast::hash(&output[crc_start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+    output.extend_from_slice(&encoded[position..]);
+    output
+}
@@ -0,0 +1,912 @@
+use crate::{ImageProcessorError, Result, metadata::Metadata};
+use std::str::FromStr;
+
+/// Which EXIF, XMP and IPTC metadata of the input is carried over into the output.
+///
+/// Tags are named as printed by exiftool (`GPSLatitude`, `SerialNumber`, `Copyright`,
+/// `DateTimeOriginal`, `CopyrightNotice`, ...), XMP properties also by their qualified
+/// name (`dc:rights`). A trailing `*` matches any suffix (`GPS*`), unknown EXIF tags are
+/// matched by their hexadecimal id (`0x9003`) and IPTC datasets by record and number (`2:116`).
+///
+/// Allow and deny lists rewrite the EXIF block, which always drops the `MakerNote`, whose
+/// vendor specific offsets would break once moved, and the thumbnail directory (IFD1), which
+/// no longer matches the processed image, even when they are listed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MetadataPolicy {
+    /// Keeps all metadata
+    #[default]
+    KeepAll,
+    /// Strips all metadata
+    StripAll,
+    /// Keeps only the listed tags
+    Allow(Vec<String>),
+    /// Keeps all but the listed tags
+    Deny(Vec<String>),
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = ImageProcessorError;
+
+    /// Parses `keep`, `strip`, `allow:<tags>` or `deny:<tags>` with comma separated tags
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, tags) = s.split_once(':').unwrap_or((s, ""));
+        let tags = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "keep" => Ok(MetadataPolicy::KeepAll),
+            "strip" => Ok(MetadataPolicy::StripAll),
+            "allow" => Ok(MetadataPolicy::Allow(tags)),
+            "deny" => Ok(MetadataPolicy::Deny(tags)),
+            _ => Err(ImageProcessorError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+impl MetadataPolicy {
+    /// Drops the metadata the policy excludes, the ICC profile is not affected
+    pub(crate) fn apply(&self, mut metadata: Metadata) -> Metadata {
+        let filter = match self {
+            MetadataPolicy::KeepAll => return metadata,
+            MetadataPolicy::StripAll => {
+                metadata.exif = None;
+                metadata.xmp = None;
+                metadata.iptc = None;
+                return metadata;
+            }
+            MetadataPolicy::Allow(tags) => TagFilter { tags, allow: true },
+            MetadataPolicy::Deny(tags) => TagFilter { tags, allow: false },
+        };
+        metadata.exif = metadata.exif.and_then(|exif| filter_exif(&exif, &filter));
+        metadata.xmp = metadata.xmp.and_then(|xmp| filter_xmp(&xmp, &filter));
+        metadata.iptc = metadata.iptc.and_then(|iptc| filter_iptc(&iptc, &filter));
+        metadata
+    }
+}
+
+/// Allow- or deny-list of tag names
+struct TagFilter<'a> {
+    tags: &'a [String],
+    allow: bool,
+}
+
+impl TagFilter<'_> {
+    /// Whether one of the names of a tag is listed
+    fn lists(&self, names: &[&str]) -> bool {
+        self.tags.iter().any(|pattern| {
+            names.iter().any(|name| match pattern.strip_suffix('*') {
+                Some(prefix) => name
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+                None => name.eq_ignore_ascii_case(pattern),
+            })
+        })
+    }
+
+    /// Whether a tag is carried over
+    fn keeps(&self, names: &[&str]) -> bool {
+        self.lists(names) == self.allow
+    }
+}
+
+/// Kinds of EXIF image file directories, the tag ids are only unique within one kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IfdKind {
+    Primary,
+    Exif,
+    Gps,
+    Interop,
+}
+
+impl IfdKind {
+    /// Kind of the directory a pointer tag of this directory refers to
+    fn sub_ifd(self, tag: u16) -> Option<IfdKind> {
+        match (self, tag) {
+            (IfdKind::Primary, 0x8769) => Some(IfdKind::Exif),
+            (IfdKind::Primary, 0x8825) => Some(IfdKind::Gps),
+            (IfdKind::Exif, 0xa005) => Some(IfdKind::Interop),
+            _ => None,
+        }
+    }
+
+    fn tag_name(self, tag: u16) -> Option<&'static str> {
+        let names: &[(u16, &str)] = match self {
+            IfdKind::Primary => PRIMARY_TAGS,
+            IfdKind::Exif => EXIF_TAGS,
+            IfdKind::Gps => GPS_TAGS,
+            IfdKind::Interop => INTEROP_TAGS,
+        };
+        names
+            .iter()
+            .find(|(id, _)| *id == tag)
+            .map(|(_, name)| *name)
+    }
+}
+
+const PRIMARY_TAGS: &[(u16, &str)] = &[
+    (0x010e, "ImageDescription"),
+    (0x010f, "Make"),
+    (0x0110, "Model"),
+    (0x0112, "Orientation"),
+    (0x011a, "XResolution"),
+    (0x011b, "YResolution"),
+    (0x0128, "ResolutionUnit"),
+    (0x0131, "Software"),
+    (0x0132, "ModifyDate"),
+    (0x013b, "Artist"),
+    (0x013c, "HostComputer"),
+    (0x013e, "WhitePoint"),
+    (0x013f, "PrimaryChromaticities"),
+    (0x0211, "YCbCrCoefficients"),
+    (0x0213, "YCbCrPositioning"),
+    (0x0214, "ReferenceBlackWhite"),
+    (0x4746, "Rating"),
+    (0x8298, "Copyright"),
+    (0x8769, "ExifOffset"),
+    (0x8825, "GPSInfo"),
+    (0x9c9b, "XPTitle"),
+    (0x9c9c, "XPComment"),
+    (0x9c9d, "XPAuthor"),
+    (0x9c9e, "XPKeywords"),
+    (0x9c9f, "XPSubject"),
+    (0xc4a5, "PrintIM"),
+];
+
+const EXIF_TAGS: &[(u16, &str)] = &[
+    (0x829a, "ExposureTime"),
+    (0x829d, "FNumber"),
+    (0x8822, "ExposureProgram"),
+    (0x8827, "ISO"),
+    (0x8830, "SensitivityType"),
+    (0x9000, "ExifVersion"),
+    (0x9003, "DateTimeOriginal"),
+    (0x9004, "CreateDate"),
+    (0x9010, "OffsetTime"),
+    (0x9011, "OffsetTimeOriginal"),
+    (0x9012, "OffsetTimeDigitized"),
+    (0x9101, "ComponentsConfiguration"),
+    (0x9102, "CompressedBitsPerPixel"),
+    (0x9201, "ShutterSpeedValue"),
+    (0x9202, "ApertureValue"),
+    (0x9203, "BrightnessValue"),
+    (0x9204, "ExposureCompensation"),
+    (0x9205, "MaxApertureValue"),
+    (0x9206, "SubjectDistance"),
+    (0x9207, "MeteringMode"),
+    (0x9208, "LightSource"),
+    (0x9209, "Flash"),
+    (0x920a, "FocalLength"),
+    (0x9214, "SubjectArea"),
+    (0x927c, "MakerNote"),
+    (0x9286, "UserComment"),
+    (0x9290, "SubSecTime"),
+    (0x9291, "SubSecTimeOriginal"),
+    (0x9292, "SubSecTimeDigitized"),
+    (0xa000, "FlashpixVersion"),
+    (0xa001, "ColorSpace"),
+    (0xa002, "ExifImageWidth"),
+    (0xa003, "ExifImageHeight"),
+    (0xa004, "RelatedSoundFile"),
+    (0xa005, "InteropOffset"),
+    (0xa20e, "FocalPlaneXResolution"),
+    (0xa20f, "FocalPlaneYResolution"),
+    (0xa210, "FocalPlaneResolutionUnit"),
+    (0xa215, "ExposureIndex"),
+    (0xa217, "SensingMethod"),
+    (0xa300, "FileSource"),
+    (0xa301, "SceneType"),
+    (0xa302, "CFAPattern"),
+    (0xa401, "CustomRendered"),
+    (0xa402, "ExposureMode"),
+    (0xa403, "WhiteBalance"),
+    (0xa404, "DigitalZoomRatio"),
+    (0xa405, "FocalLengthIn35mmFormat"),
+    (0xa406, "SceneCaptureType"),
+    (0xa407, "GainControl"),
+    (0xa408, "Contrast"),
+    (0xa409, "Saturation"),
+    (0xa40a, "Sharpness"),
+    (0xa40c, "SubjectDistanceRange"),
+    (0xa420, "ImageUniqueID"),
+    (0xa430, "OwnerName"),
+    (0xa431, "SerialNumber"),
+    (0xa432, "LensInfo"),
+    (0xa433, "LensMake"),
+    (0xa434, "LensModel"),
+    (0xa435, "LensSerialNumber"),
+];
+
+const GPS_TAGS: &[(u16, &str)] = &[
+    (0x0000, "GPSVersionID"),
+    (0x0001, "GPSLatitudeRef"),
+    (0x0002, "GPSLatitude"),
+    (0x0003, "GPSLongitudeRef"),
+    (0x0004, "GPSLongitude"),
+    (0x0005, "GPSAltitudeRef"),
+    (0x0006, "GPSAltitude"),
+    (0x0007, "GPSTimeStamp"),
+    (0x0008, "GPSSatellites"),
+    (0x0009, "GPSStatus"),
+    (0x000a, "GPSMeasureMode"),
+    (0x000b, "GPSDOP"),
+    (0x000c, "GPSSpeedRef"),
+    (0x000d, "GPSSpeed"),
+    (0x000e, "GPSTrackRef"),
+    (0x000f, "GPSTrack"),
+    (0x0010, "GPSImgDirectionRef"),
+    (0x0011, "GPSImgDirection"),
+    (0x0012, "GPSMapDatum"),
+    (0x0013, "GPSDestLatitudeRef"),
+    (0x0014, "GPSDestLatitude"),
+    (0x0015, "GPSDestLongitudeRef"),
+    (0x0016, "GPSDestLongitude"),
+    (0x0017, "GPSDestBearingRef"),
+    (0x0018, "GPSDestBearing"),
+    (0x0019, "GPSDestDistanceRef"),
+    (0x001a, "GPSDestDistance"),
+    (0x001b, "GPSProcessingMethod"),
+    (0x001c, "GPSAreaInformation"),
+    (0x001d, "GPSDateStamp"),
+    (0x001e, "GPSDifferential"),
+    (0x001f, "GPSHPositioningError"),
+];
+
+const INTEROP_TAGS: &[(u16, &str)] = &[(0x0001, "InteropIndex"), (0x0002, "InteropVersion")];
+
+/// Byte order of a TIFF structure
+#[derive(Debug, Clone, Copy)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, bytes: &[u8], offset: usize) -> Option<u16> {
+        let bytes = bytes.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(self, bytes: &[u8], offset: usize) -> Option<u32> {
+        let bytes = bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn u16_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Deepest nesting of directories followed, guards against pointer cycles
+const MAX_IFD_DEPTH: usize = 3;
+
+/// Entry of an EXIF image file directory with its value or sub-directory
+#[derive(Debug)]
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    value: Vec<u8>,
+    sub_ifd: Option<(IfdKind, Vec<IfdEntry>)>,
+}
+
+/// Rewrites the EXIF block without the tags the filter excludes. The thumbnail directory is
+/// always dropped as it would need filtering too and no longer matches the image anyway, the
+/// maker note as its internal offsets are not known to be relative to it. Blocks which cannot
+/// be parsed are dropped entirely.
+fn filter_exif(exif: &[u8], filter: &TagFilter) -> Option<Vec<u8>> {
+    let order = match exif.get(0..4)? {
+        b"II*\0" => ByteOrder::Little,
+        b"MM\0*" => ByteOrder::Big,
+        _ => return None,
+    };
+    let offset = order.u32(exif, 4)? as usize;
+    let entries = read_ifd(exif, offset, order, IfdKind::Primary, 0)?;
+    let entries = filter_ifd(entries, IfdKind::Primary, filter);
+    if entries.is_empty() {
+        return None;
+    }
+    let mut output = exif[0..4].to_vec();
+    output.extend_from_slice(&order.u32_bytes(8));
+    write_ifd(&mut output, &entries, order);
+    Some(output)
+}
+
+fn read_ifd(
+    exif: &[u8],
+    offset: usize,
+    order: ByteOrder,
+    kind: IfdKind,
+    depth: usize,
+) -> Option<Vec<IfdEntry>> {
+    let count = order.u16(exif, offset)? as usize;
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let field = offset + 2 + i * 12;
+        let tag = order.u16(exif, field)?;
+        let field_type = order.u16(exif, field + 2)?;
+        let count = order.u32(exif, field + 4)?;
+        let type_size = match field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 => 8,
+            // Entries of unknown types cannot be relocated
+            _ => continue,
+        };
+        let size = type_size * count as usize;
+        let value = if size <= 4 {
+            exif.get(field + 8..field + 8 + size)
+        } else {
+            let value_offset = order.u32(exif, field + 8)? as usize;
+            exif.get(value_offset..value_offset + size)
+        };
+        let Some(value) = value else {
+            continue;
+        };
+        let sub_ifd = match kind.sub_ifd(tag) {
+            Some(sub_kind) if depth < MAX_IFD_DEPTH => {
+                let sub_offset = order.u32(exif, field + 8)? as usize;
+                let sub_entries = read_ifd(exif, sub_offset, order, sub_kind, depth + 1)?;
+                Some((sub_kind, sub_entries))
+            }
+            Some(_) => continue,
+            None => None,
+        };
+        entries.push(IfdEntry {
+            tag,
+            field_type,
+            count,
+            value: value.to_vec(),
+            sub_ifd,
+        });
+    }
+    Some(entries)
+}
+
+/// Tag of the maker note in the Exif directory
+const MAKER_NOTE: u16 = 0x927c;
+
+fn filter_ifd(entries: Vec<IfdEntry>, kind: IfdKind, filter: &TagFilter) -> Vec<IfdEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| kind != IfdKind::Exif || entry.tag != MAKER_NOTE)
+        .filter_map(|mut entry| {
+            let id = format!("{:#06x}", entry.tag);
+            let names = match kind.tag_name(entry.tag) {
+                Some(name) => vec![name, id.as_str()],
+                None => vec![id.as_str()],
+            };
+            match entry.sub_ifd.take() {
+                // A listed directory is kept or dropped as a whole
+                Some(sub_ifd) if filter.lists(&names) => filter.allow.then_some(IfdEntry {
+                    sub_ifd: Some(sub_ifd),
+                    ..entry
+                }),
+                Some((sub_kind, sub_entries)) => {
+                    let sub_entries = filter_ifd(sub_entries, sub_kind, filter);
+                    (!sub_entries.is_empty()).then_some(IfdEntry {
+                        sub_ifd: Some((sub_kind, sub_entries)),
+                        ..entry
+                    })
+                }
+                None => filter.keeps(&names).then_some(entry),
+            }
+        })
+        .collect()
+}
+
+/// Appends the directory followed by its values and sub-directories, returning its offset
+fn write_ifd(output: &mut Vec<u8>, entries: &[IfdEntry], order: ByteOrder) -> u32 {
+    if output.len() % 2 == 1 {
+        output.push(0);
+    }
+    let start = output.len();
+    output.extend_from_slice(&order.u16_bytes(entries.len() as u16));
+    output.resize(start + 2 + entries.len() * 12, 0);
+    // No next directory, the thumbnail directory is not carried over
+    output.extend_from_slice(&order.u32_bytes(0));
+    for (i, entry) in entries.iter().enumerate() {
+        let field = start + 2 + i * 12;
+        let mut value = [0; 4];
+        if let Some((_, sub_entries)) = &entry.sub_ifd {
+            value = order.u32_bytes(write_ifd(output, sub_entries, order));
+        } else if entry.value.len() <= 4 {
+            value[..entry.value.len()].copy_from_slice(&entry.value);
+        } else {
+            if output.len() % 2 == 1 {
+                output.push(0);
+            }
+            value = order.u32_bytes(output.len() as u32);
+            output.extend_from_slice(&entry.value);
+        }
+        output[field..field + 2].copy_from_slice(&order.u16_bytes(entry.tag));
+        output[field + 2..field + 4].copy_from_slice(&order.u16_bytes(entry.field_type));
+        output[field + 4..field + 8].copy_from_slice(&order.u32_bytes(entry.count));
+        output[field + 8..field + 12].copy_from_slice(&value);
+    }
+    start as u32
+}
+
+/// Piece of an XML document
+#[derive(Debug, PartialEq)]
+enum XmlToken<'a> {
+    /// Start tag, its element name and the whole tag
+    Start(&'a str, &'a str),
+    /// Empty element tag, its element name and the whole tag
+    Empty(&'a str, &'a str),
+    /// End tag with its element name
+    End(&'a str),
+    /// Text, comments, processing instructions and declarations
+    Other(&'a str),
+}
+
+/// Splits an XML document into tokens, `None` if it is malformed
+fn tokenize_xml(xml: &str) -> Option<Vec<XmlToken<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = xml;
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(XmlToken::Other(&rest[..end]));
+            rest = &rest[end..];
+            continue;
+        }
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->")? + 3
+        } else if rest.starts_with("<![CDATA[") {
+            rest.find("]]>")? + 3
+        } else if rest.starts_with("<?") {
+            rest.find("?>")? + 2
+        } else {
+            // Attribute values may contain `>`
+            let mut quote = None;
+            let end = rest.char_indices().find(|&(_, c)| {
+                match quote {
+                    Some(q) if c == q => quote = None,
+                    Some(_) => {}
+                    None if c == '"' || c == '\'' => quote = Some(c),
+                    None => return c == '>',
+                }
+                false
+            })?;
+            end.0 + 1
+        };
+        let raw = &rest[..end];
+        rest = &rest[end..];
+        let name_of = |tag: &'_ str| {
+            tag.split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+                .next()
+                .unwrap_or("")
+                .to_string()
+        };
+        let token = if raw.starts_with("<!") || raw.starts_with("<?") {
+            XmlToken::Other(raw)
+        } else if let Some(tag) = raw.strip_prefix("</") {
+            let len = name_of(tag).len();
+            XmlToken::End(&tag[..len])
+        } else if raw.ends_with("/>") {
+            let len = name_of(&raw[1..]).len();
+            XmlToken::Empty(&raw[1..1 + len], raw)
+        } else {
+            let len = name_of(&raw[1..]).len();
+            XmlToken::Start(&raw[1..1 + len], raw)
+        };
+        tokens.push(token);
+    }
+    Some(tokens)
+}
+
+/// Names an XMP property is matched by, its qualified and its local name
+fn xmp_names(name: &str) -> [&str; 2] {
+    [name, name.split_once(':').map_or(name, |(_, local)| local)]
+}
+
+/// Drops the excluded properties from the attributes of an `rdf:Description` tag
+fn filter_description_tag(tag: &str, filter: &TagFilter) -> String {
+    let (open, close) = match tag.strip_suffix("/>") {
+        Some(open) => (open, "/>"),
+        None => (&tag[..tag.len() - 1], ">"),
+    };
+    let mut output = String::from("<rdf:Description");
+    let mut rest = &open["<rdf:Description".len()..];
+    loop {
+        rest = rest.trim_start();
+        let Some(eq) = rest.find('=') else {
+            break;
+        };
+        let name = rest[..eq].trim();
+        let value_start = rest[eq + 1..].trim_start();
+        let Some(quote) = value_start.chars().next() else {
+            break;
+        };
+        let Some(value_len) = value_start[1..].find(quote) else {
+            break;
+        };
+        let value = &value_start[..value_len + 2];
+        let structural = name.starts_with("xmlns") || name.starts_with("rdf:");
+        if structural || filter.keeps(&xmp_names(name)) {
+            output.push(' ');
+            output.push_str(name);
+            output.push('=');
+            output.push_str(value);
+        }
+        rest = &value_start[value_len + 2..];
+    }
+    output.push_str(close);
+    output
+}
+
+/// Rewrites the XMP packet without the top level properties the filter excludes, packets
+/// which cannot be parsed are dropped entirely
+fn filter_xmp(xmp: &[u8], filter: &TagFilter) -> Option<Vec<u8>> {
+    let tokens = tokenize_xml(std::str::from_utf8(xmp).ok()?)?;
+    let mut output = String::new();
+    // Names of the open elements, and how many were open when skipping an excluded property
+    let mut open = Vec::new();
+    let mut skipping = None;
+    for token in tokens {
+        let in_rdf = open.last() == Some(&"rdf:RDF");
+        let excluded = |name| {
+            open.ends_with(&["rdf:RDF", "rdf:Description"]) && !filter.keeps(&xmp_names(name))
+        };
+        match token {
+            XmlToken::Start(name, raw) => {
+                if skipping.is_some() {
+                } else if excluded(name) {
+                    skipping = Some(open.len());
+                } else if in_rdf && name == "rdf:Description" {
+                    output.push_str(&filter_description_tag(raw, filter));
+                } else {
+                    output.push_str(raw);
+                }
+                open.push(name);
+            }
+            XmlToken::Empty(name, raw) => {
+                if skipping.is_some() || excluded(name) {
+                } else if in_rdf && name == "rdf:Description" {
+                    output.push_str(&filter_description_tag(raw, filter));
+                } else {
+                    output.push_str(raw);
+                }
+            }
+            XmlToken::End(name) => {
+                open.pop()?;
+                match skipping {
+                    Some(len) if len == open.len() => skipping = None,
+                    Some(_) => {}
+                    None => {
+                        output.push_str("</");
+                        output.push_str(name);
+                        output.push('>');
+                    }
+                }
+            }
+            XmlToken::Other(raw) => {
+                if skipping.is_none() {
+                    output.push_str(raw);
+                }
+            }
+        }
+    }
+    open.is_empty().then(|| output.into_bytes())
+}
+
+/// Names of the IPTC application record datasets
+const IPTC_DATASETS: &[(u8, &str)] = &[
+    (0, "ApplicationRecordVersion"),
+    (3, "ObjectTypeReference"),
+    (4, "ObjectAttributeReference"),
+    (5, "ObjectName"),
+    (7, "EditStatus"),
+    (10, "Urgency"),
+    (12, "SubjectReference"),
+    (15, "Category"),
+    (20, "SupplementalCategories"),
+    (22, "FixtureIdentifier"),
+    (25, "Keywords"),
+    (26, "ContentLocationCode"),
+    (27, "ContentLocationName"),
+    (30, "ReleaseDate"),
+    (35, "ReleaseTime"),
+    (37, "ExpirationDate"),
+    (38, "ExpirationTime"),
+    (40, "SpecialInstructions"),
+    (42, "ActionAdvised"),
+    (45, "ReferenceService"),
+    (47, "ReferenceDate"),
+    (50, "ReferenceNumber"),
+    (55, "DateCreated"),
+    (60, "TimeCreated"),
+    (62, "DigitalCreationDate"),
+    (63, "DigitalCreationTime"),
+    (65, "OriginatingProgram"),
+    (70, "ProgramVersion"),
+    (75, "ObjectCycle"),
+    (80, "By-line"),
+    (85, "By-lineTitle"),
+    (90, "City"),
+    (92, "Sub-location"),
+    (95, "Province-State"),
+    (100, "Country-PrimaryLocationCode"),
+    (101, "Country-PrimaryLocationName"),
+    (103, "OriginalTransmissionReference"),
+    (105, "Headline"),
+    (110, "Credit"),
+    (115, "Source"),
+    (116, "CopyrightNotice"),
+    (118, "Contact"),
+    (120, "Caption-Abstract"),
+    (121, "LocalCaption"),
+    (122, "Writer-Editor"),
+    (130, "ImageType"),
+    (131, "ImageOrientation"),
+    (135, "LanguageIdentifier"),
+];
+
+/// Photoshop image resource holding the IPTC datasets
+const IPTC_RESOURCE_ID: u16 = 0x0404;
+
+/// Rewrites the Photoshop image resources into a single IPTC resource without the datasets
+/// the filter excludes. The other resources (thumbnails, digests, ...) are dropped.
+fn filter_iptc(resources: &[u8], filter: &TagFilter) -> Option<Vec<u8>> {
+    let iptc = find_image_resource(resources, IPTC_RESOURCE_ID)?;
+    let mut datasets = Vec::new();
+    let mut kept_any = false;
+    let mut rest = iptc;
+    while let [0x1c, record, number, high, low, ..] = *rest {
+        let (header, size) = if high & 0x80 == 0 {
+            (5, u16::from_be_bytes([high, low]) as usize)
+        } else {
+            // Extended datasets store their size in the given number of following bytes
+            let len = u16::from_be_bytes([high & 0x7f, low]) as usize;
+            let size = rest
+                .get(5..5 + len)?
+                .iter()
+                .fold(0usize, |size, &b| (size << 8) | b as usize);
+            (5 + len, size)
+        };
+        let dataset = rest.get(..header + size)?;
+        rest = &rest[header + size..];
+        // The record version and character set are needed to interpret the rest
+        let structural = (record, number) == (2, 0) || (record, number) == (1, 90);
+        let id = format!("{}:{}", record, number);
+        let name = IPTC_DATASETS
+            .iter()
+            .find(|(n, _)| record == 2 && *n == number)
+            .map_or("", |(_, name)| name);
+        if structural || filter.keeps(&[name, id.as_str()]) {
+            kept_any |= !structural;
+            datasets.extend_from_slice(dataset);
+        }
+    }
+    if !kept_any {
+        return None;
+    }
+    let mut output = b"8BIM".to_vec();
+    output.extend_from_slice(&IPTC_RESOURCE_ID.to_be_bytes());
+    // Empty name padded to an even length
+    output.extend_from_slice(&[0, 0]);
+    output.extend_from_slice(&(datasets.len() as u32).to_be_bytes());
+    output.extend_from_slice(&datasets);
+    if datasets.len() % 2 == 1 {
+        output.push(0);
+    }
+    Some(output)
+}
+
+/// Finds the payload of a resource in a Photoshop image resource block
+fn find_image_resource(mut resources: &[u8], id: u16) -> Option<&[u8]> {
+    while resources.len() >= 12 && &resources[0..4] == b"8BIM" {
+        let resource_id = u16::from_be_bytes([resources[4], resources[5]]);
+        // Pascal string name padded to an even length
+        let name_len = (resources[6] as usize + 2) & !1;
+        let size_at = 6 + name_len;
+        let size = u32::from_be_bytes(resources.get(size_at..size_at + 4)?.try_into().ok()?);
+        let data = resources.get(size_at + 4..size_at + 4 + size as usize)?;
+        if resource_id == id {
+            return Some(data);
+        }
+        let end = (size_at + 4 + size as usize + 1) & !1;
+        resources = resources.get(end..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Big endian EXIF block with a make, a copyright, a serial number and a GPS position
+    fn exif() -> Vec<u8> {
+        let mut exif = b"MM\0*\0\0\0\x08".to_vec();
+        // IFD0 at 8: Make, Copyright, ExifOffset, GPSInfo
+        exif.extend_from_slice(&4u16.to_be_bytes());
+        exif.extend_from_slice(&[0x01, 0x0f, 0, 2, 0, 0, 0, 4, b'A', b'c', b'm', 0]);
+        exif.extend_from_slice(&[0x82, 0x98, 0, 2, 0, 0, 0, 4, b'M', b'e', b'!', 0]);
+        exif.extend_from_slice(&[0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 62]);
+        exif.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 80]);
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        // Exif IFD at 62: SerialNumber
+        exif.extend_from_slice(&1u16.to_be_bytes());
+        exif.extend_from_slice(&[0xa4, 0x31, 0, 2, 0, 0, 0, 4, b'4', b'2', b'1', 0]);
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        // GPS IFD at 80: GPSLatitudeRef, GPSLatitude
+        exif.extend_from_slice(&2u16.to_be_bytes());
+        exif.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+        exif.extend_from_slice(&[0, 2, 0, 5, 0, 0, 0, 3, 0, 0, 0, 110]);
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        // GPS latitude at 110
+        for value in [48u32, 1, 51, 1, 30, 1] {
+            exif.extend_from_slice(&value.to_be_bytes());
+        }
+        exif
+    }
+
+    fn tags(exif: &[u8]) -> Vec<u16> {
+        fn collect(entries: &[IfdEntry], tags: &mut Vec<u16>) {
+            for entry in entries {
+                tags.push(entry.tag);
+                if let Some((_, sub_entries)) = &entry.sub_ifd {
+                    collect(sub_entries, tags);
+                }
+            }
+        }
+        let entries = read_ifd(exif, 8, ByteOrder::Big, IfdKind::Primary, 0).unwrap();
+        let mut tags = Vec::new();
+        collect(&entries, &mut tags);
+        tags
+    }
+
+    fn filter(policy: &str) -> TagFilter<'static> {
+        let tags = match policy.parse::<MetadataPolicy>().unwrap() {
+            MetadataPolicy::Allow(tags) | MetadataPolicy::Deny(tags) => tags,
+            _ => unreachable!(),
+        };
+        TagFilter {
+            tags: Vec::leak(tags),
+            allow: policy.starts_with("allow"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "keep".parse::<MetadataPolicy>().unwrap(),
+            MetadataPolicy::KeepAll
+        );
+        assert_eq!(
+            "Strip".parse::<MetadataPolicy>().unwrap(),
+            MetadataPolicy::StripAll
+        );
+        assert_eq!(
+            "deny:GPS*, SerialNumber".parse::<MetadataPolicy>().unwrap(),
+            MetadataPolicy::Deny(vec!["GPS*".to_string(), "SerialNumber".to_string()])
+        );
+        assert!("remove:GPS*".parse::<MetadataPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_exif_filter() {
+        let exif = exif();
+        assert_eq!(
+            tags(&exif),
+            [0x010f, 0x8298, 0x8769, 0xa431, 0x8825, 0x0001, 0x0002]
+        );
+
+        let denied = filter_exif(&exif, &filter("deny:GPS*,serialnumber")).unwrap();
+        assert_eq!(tags(&denied), [0x010f, 0x8298]);
+
+        let allowed = filter_exif(&exif, &filter("allow:Copyright,GPSLatitude")).unwrap();
+        assert_eq!(tags(&allowed), [0x8298, 0x8825, 0x0002]);
+        let entries = read_ifd(&allowed, 8, ByteOrder::Big, IfdKind::Primary, 0).unwrap();
+        assert_eq!(entries[0].value, b"Me!\0");
+        let (_, gps) = entries[1].sub_ifd.as_ref().unwrap();
+        assert_eq!(gps[0].value, exif[110..134]);
+
+        let whole_directory = filter_exif(&exif, &filter("allow:0x8825")).unwrap();
+        assert_eq!(tags(&whole_directory), [0x8825, 0x0001, 0x0002]);
+        assert_eq!(filter_exif(&exif, &filter("allow:Artist")), None);
+        assert_eq!(filter_exif(b"garbage", &filter("deny:GPS*")), None);
+    }
+
+    #[test]
+    fn test_maker_note_and_thumbnail() {
+        let mut exif = b"MM\0*\0\0\0\x08".to_vec();
+        // IFD0 at 8: ExifOffset, followed by the thumbnail directory at 68
+        exif.extend_from_slice(&1u16.to_be_bytes());
+        exif.extend_from_slice(&[0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 26]);
+        exif.extend_from_slice(&68u32.to_be_bytes());
+        // Exif IFD at 26: SerialNumber, MakerNote
+        exif.extend_from_slice(&2u16.to_be_bytes());
+        exif.extend_from_slice(&[0xa4, 0x31, 0, 2, 0, 0, 0, 4, b'4', b'2', b'1', 0]);
+        exif.extend_from_slice(&[0x92, 0x7c, 0, 7, 0, 0, 0, 12, 0, 0, 0, 56]);
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        // Maker note at 56, pointing at its own data by offsets into the whole block
+        exif.extend_from_slice(b"Nikon\0\0\0\0\0\0\x3e");
+        // IFD1 at 68: JPEGInterchangeFormat
+        exif.extend_from_slice(&1u16.to_be_bytes());
+        exif.extend_from_slice(&[0x02, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0]);
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(tags(&exif), [0x8769, 0xa431, 0x927c]);
+
+        for policy in ["deny:GPS*", "allow:SerialNumber,MakerNote,0x0201"] {
+            let filtered = filter_exif(&exif, &filter(policy)).unwrap();
+            assert_eq!(tags(&filtered), [0x8769, 0xa431], "{}", policy);
+            // No directory follows IFD0
+            assert_eq!(ByteOrder::Big.u32(&filtered, 8 + 2 + 12), Some(0));
+        }
+        assert_eq!(filter_exif(&exif, &filter("allow:MakerNote")), None);
+    }
+
+    #[test]
+    fn test_xmp_filter() {
+        let xmp = concat!(
+            "<?xpacket begin=\"\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF>",
+            "<rdf:Description rdf:about=\"\" xmlns:exif=\"http://ns.adobe.com/exif/1.0/\" ",
+            "exif:GPSLatitude=\"48,51.5N\" exif:DateTimeOriginal='2024-05-01T10:00:00'>",
+            "<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">Me</rdf:li></rdf:Alt></dc:rights>",
+            "<aux:SerialNumber>421</aux:SerialNumber><exif:GPSAltitude/>",
+            "</rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+        );
+        let denied = filter_xmp(xmp.as_bytes(), &filter("deny:GPS*,SerialNumber")).unwrap();
+        assert_eq!(
+            String::from_utf8(denied).unwrap(),
+            concat!(
+                "<?xpacket begin=\"\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF>",
+                "<rdf:Description rdf:about=\"\" xmlns:exif=\"http://ns.adobe.com/exif/1.0/\" ",
+                "exif:DateTimeOriginal='2024-05-01T10:00:00'>",
+                "<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">Me</rdf:li></rdf:Alt></dc:rights>",
+                "</rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+            )
+        );
+
+        let allowed = filter_xmp(xmp.as_bytes(), &filter("allow:dc:rights")).unwrap();
+        let allowed = String::from_utf8(allowed).unwrap();
+        assert!(allowed.contains("<dc:rights>"));
+        assert!(!allowed.contains("GPS") && !allowed.contains("DateTimeOriginal"));
+        assert_eq!(filter_xmp(b"<a><b></a>", &filter("deny:b")), None);
+    }
+
+    #[test]
+    fn test_iptc_filter() {
+        let mut datasets = Vec::new();
+        for (number, value) in [(0u8, &b"\0\x04"[..]), (116, b"Me"), (90, b"Paris")] {
+            datasets.extend_from_slice(&[0x1c, 2, number, 0, value.len() as u8]);
+            datasets.extend_from_slice(value);
+        }
+        let mut resources = b"8BIM\x04\x25\0\0\0\0\0\x02ab".to_vec();
+        resources.extend_from_slice(b"8BIM\x04\x04\0\0");
+        resources.extend_from_slice(&(datasets.len() as u32).to_be_bytes());
+        resources.extend_from_slice(&datasets);
+
+        let filtered = filter_iptc(&resources, &filter("deny:City")).unwrap();
+        let iptc = find_image_resource(&filtered, IPTC_RESOURCE_ID).unwrap();
+        assert_eq!(iptc, &datasets[..14]);
+        assert_eq!(find_image_resource(&filtered, 0x0425), None);
+
+        let filtered = filter_iptc(&resources, &filter("allow:2:90")).unwrap();
+        let iptc = find_image_resource(&filtered, IPTC_RESOURCE_ID).unwrap();
+        assert_eq!(&iptc[..7], &datasets[..7]);
+        assert_eq!(&iptc[7..], &datasets[14..]);
+        assert_eq!(filter_iptc(&resources, &filter("allow:Keywords")), None);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    ImageProcessor, ImageProcessorError, ImageProcessorFactory, ImageSource, JpegProcessor,
+    PngProcessor, Result, sniff,
+};
+use image::ImageFormat;
+use std::path::Path;
+
+/// Creates a processor for an input image
+type Constructor = Box<dyn Fn(ImageSource) -> Result<Box<dyn ImageProcessor>> + Send + Sync>;
+
+/// Processor registered with a [`ProcessorRegistry`], chosen for images whose leading bytes
+/// or file extension it matches
+pub struct Registration {
+    formats: Vec<ImageFormat>,
+    magic: Vec<Vec<u8>>,
+    extensions: Vec<String>,
+    priority: i32,
+    constructor: Constructor,
+}
+
+impl Registration {
+    /// Registers the processors the constructor creates, matching no image until formats,
+    /// magic bytes or extensions are added
+    pub fn new(
+        constructor: impl Fn(ImageSource) -> Result<Box<dyn ImageProcessor>> + Send + Sync + 'static,
+    ) -> Self {
+        Registration {
+            formats: Vec::new(),
+            magic: Vec::new(),
+            extensions: Vec::new(),
+            priority: 0,
+            constructor: Box::new(constructor),
+        }
+    }
+
+    /// Matches images the image crate detects as the format, by content or by extension
+    pub fn with_format(mut self, format: ImageFormat) -> Self {
+        self.formats.push(format);
+        self
+    }
+
+    /// Matches images starting with the given bytes
+    pub fn with_magic(mut self, magic: &[u8]) -> Self {
+        self.magic.push(magic.to_vec());
+        self
+    }
+
+    /// Matches files with the given extension, compared case-insensitively, when their
+    /// content is not recognized
+    pub fn with_extension(mut self, extension: &str) -> Self {
+        self.extensions.push(extension.to_ascii_lowercase());
+        self
+    }
+
+    /// Sets the priority, the highest priority registration matching an image wins
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn matches_content(&self, header: &[u8], detected: Option<ImageFormat>) -> bool {
+        self.magic.iter().any(|magic| header.starts_with(magic))
+            || detected.is_some_and(|detected| self.formats.contains(&detected))
+    }
+
+    fn matches_extension(&self, extension: &str) -> bool {
+        self.extensions.iter().any(|e| e == extension)
+            || ImageFormat::from_extension(extension)
+                .is_some_and(|format| self.formats.contains(&format))
+    }
+}
+
+/// Factory choosing among registered processors by the content and extension of images
+pub struct ProcessorRegistry {
+    registrations: Vec<Registration>,
+}
+
+impl ProcessorRegistry {
+    /// Creates a registry of the built-in JPEG and PNG processors
+    pub fn new() -> Self {
+        ProcessorRegistry::empty()
+            .with(
+                Registration::new(|source| Ok(Box::new(JpegProcessor { source })))
+                    .with_format(ImageFormat::Jpeg),
+            )
+            .with(
+                Registration::new(|source| Ok(Box::new(PngProcessor { source })))
+                    .with_format(ImageFormat::Png),
+            )
+    }
+
+    /// Creates a registry without any processors, for custom sets of processors
+    pub fn empty() -> Self {
+        ProcessorRegistry {
+            registrations: Vec::new(),
+        }
+    }
+
+    /// Adds a processor, on equal priority earlier registrations win
+    pub fn register(&mut self, registration: Registration) {
+        self.registrations.push(registration);
+    }
+
+    /// Adds a processor and returns the registry
+    pub fn with(mut self, registration: Registration) -> Self {
+        self.register(registration);
+        self
+    }
+
+    /// Highest priority registration satisfying the predicate
+    fn best(&self, predicate: impl Fn(&Registration) -> bool) -> Option<&Registration> {
+        self.registrations
+            .iter()
+            .filter(|registration| predicate(registration))
+            .rev()
+            .max_by_key(|registration| registration.priority)
+    }
+
+    /// Chooses the processor by the leading bytes of the image, falling back to the
+    /// extension when the content is not recognized
+    fn processor_for(
+        &self,
+        header: &[u8],
+        extension: Option<&str>,
+        source: ImageSource,
+    ) -> Result<Box<dyn ImageProcessor>> {
+        let detected = image::guess_format(header).ok();
+        let extension = extension.map(str::to_ascii_lowercase);
+        let extension_format = extension.as_deref().and_then(ImageFormat::from_extension);
+        if let (Some(detected), Some(extension)) = (detected, extension_format)
+            && detected != extension
+        {
+            return Err(ImageProcessorError::FormatMismatch {
+                detected,
+                extension,
+            });
+        }
+        let registration = match self.best(|r| r.matches_content(header, detected)) {
+            Some(registration) => registration,
+            // Known content is never reinterpreted by its extension
+            None if detected.is_some() => return Err(ImageProcessorError::UnsupportedFormat),
+            None => extension
+                .and_then(|extension| self.best(|r| r.matches_extension(&extension)))
+                .ok_or(ImageProcessorError::UnsupportedFormat)?,
+        };
+        (registration.constructor)(source)
+    }
+}
+
+/// Registry of the built-in JPEG and PNG processors
+impl Default for ProcessorRegistry {
+    fn default() -> Self {
+        ProcessorRegistry::new()
+    }
+}
+
+impl ImageProcessorFactory for ProcessorRegistry {
+    fn process_image(&self, image: &Path) -> Result<Box<dyn ImageProcessor>> {
+        let header = sniff(image);
+        let extension = image.extension().and_then(|extension| extension.to_str());
+        self.processor_for(&header, extension, ImageSource::File(image.to_path_buf()))
+    }
+
+    fn process_bytes(&self, image: &[u8]) -> Result<Box<dyn ImageProcessor>> {
+        self.processor_for(image, None, ImageSource::Memory(image.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Quality, ShrinkOptions, ShrinkReport};
+    use std::io::Write;
+
+    /// Processor of a made-up format writing a marker instead of shrinking
+    struct MarkerProcessor(&'static [u8]);
+
+    impl ImageProcessor for MarkerProcessor {
+        fn shrink_into(
+            &self,
+            output: &mut dyn Write,
+            _options: &ShrinkOptions,
+        ) -> Result<ShrinkReport> {
+            output
+                .write_all(self.0)
+                .map_err(|source| ImageProcessorError::WriteOutput { path: None, source })?;
+            Err(ImageProcessorError::UnsupportedFormat)
+        }
+    }
+
+    fn marker_of(processor: Result<Box<dyn ImageProcessor>>) -> Vec<u8> {
+        let mut output = Vec::new();
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap());
+        processor.unwrap().shrink_into(&mut output, &options).ok();
+        output
+    }
+
+    #[test]
+    fn test_custom_format() {
+        let registry = ProcessorRegistry::default().with(
+            Registration::new(|_| Ok(Box::new(MarkerProcessor(b"custom"))))
+                .with_magic(b"CUSTOM")
+                .with_extension("CST"),
+        );
+        assert_eq!(
+            marker_of(registry.process_bytes(b"CUSTOM image data")),
+            b"custom"
+        );
+        // Unrecognized content falls back to the extension
+        assert_eq!(
+            marker_of(registry.process_image(Path::new("missing.cst"))),
+            b"custom"
+        );
+        assert!(registry.process_bytes(b"unknown image data").is_err());
+        assert!(registry.process_image(Path::new("test.jpg")).is_ok());
+    }
+
+    #[test]
+    fn test_priority() {
+        let input = std::fs::read("test.jpg").unwrap();
+        let jpeg = |marker: &'static [u8], priority| {
+            Registration::new(move |_| Ok(Box::new(MarkerProcessor(marker))))
+                .with_format(ImageFormat::Jpeg)
+                .with_priority(priority)
+        };
+        let registry = ProcessorRegistry::empty()
+            .with(jpeg(b"first", 0))
+            .with(jpeg(b"second", 0));
+        assert_eq!(marker_of(registry.process_bytes(&input)), b"first");
+        let registry = registry.with(jpeg(b"override", 1));
+        assert_eq!(marker_of(registry.process_bytes(&input)), b"override");
+        assert!(matches!(
+            registry.process_image(Path::new("test.png")),
+            Err(ImageProcessorError::UnsupportedFormat)
+        ));
+
+        // The built-in processors can be overridden as well
+        let registry = ProcessorRegistry::new().with(jpeg(b"override", 1));
+        assert_eq!(marker_of(registry.process_bytes(&input)), b"override");
+        assert!(ProcessorRegistry::empty().process_bytes(&input).is_err());
+        assert!(ProcessorRegistry::new().process_bytes(&input).is_ok());
+        assert!(ProcessorRegistry::default().process_bytes(&input).is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{ImageProcessorError, Result};
+use image::{DynamicImage, GenericImageView, imageops};
+use std::str::FromStr;
+
+/// How an image is fitted into the maximum dimensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeMode {
+    /// Scale down preserving the aspect ratio until both dimensions fit
+    #[default]
+    Fit,
+    /// Scale down preserving the aspect ratio until the box is covered, then crop the overflow
+    Fill,
+    /// Scale to exactly the maximum dimensions, ignoring the aspect ratio
+    Exact,
+}
+
+impl FromStr for ResizeMode {
+    type Err = ImageProcessorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fit" => Ok(ResizeMode::Fit),
+            "fill" => Ok(ResizeMode::Fill),
+            "exact" => Ok(ResizeMode::Exact),
+            _ => Err(ImageProcessorError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+/// Resampling filter used when resizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeFilter {
+    /// Nearest neighbor, fastest and blockiest
+    Nearest,
+    /// Linear
+    Triangle,
+    /// Cubic
+    CatmullRom,
+    /// Gaussian
+    Gaussian,
+    /// Lanczos with window 3, slowest and sharpest
+    #[default]
+    Lanczos3,
+}
+
+impl FromStr for ResizeFilter {
+    type Err = ImageProcessorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" => Ok(ResizeFilter::Triangle),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "gaussian" => Ok(ResizeFilter::Gaussian),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(ImageProcessorError::InvalidValue(s.to_string())),
+        }
+    }
+}
+
+impl From<ResizeFilter> for imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => imageops::FilterType::CatmullRom,
+            ResizeFilter::Gaussian => imageops::FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Maximum dimensions an image is resized to, a missing dimension is unconstrained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resize {
+    /// Maximum width in pixels
+    pub max_width: Option<u32>,
+    /// Maximum height in pixels
+    pub max_height: Option<u32>,
+    /// How the image is fitted into the maximum dimensions
+    pub mode: ResizeMode,
+    /// Resampling filter
+    pub filter: ResizeFilter,
+}
+
+impl Resize {
+    /// Creates a resize fitting the image into the given dimensions with the default filter
+    pub fn new(max_width: Option<u32>, max_height: Option<u32>) -> Self {
+        Resize {
+            max_width,
+            max_height,
+            mode: ResizeMode::default(),
+            filter: ResizeFilter::default(),
+        }
+    }
+
+    /// Sets how the image is fitted into the maximum dimensions
+    pub fn with_mode(mut self, mode: ResizeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the resampling filter
+    pub fn with_filter(mut self, filter: ResizeFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Resizes the image, images already within the maximum dimensions are never upscaled
+    /// except in the exact mode
+    pub(crate) fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = image.dimensions();
+        let max_width = self.max_width.unwrap_or(width).max(1);
+        let max_height = self.max_height.unwrap_or(height).max(1);
+        let fits = width <= max_width && height <= max_height;
+        let filter = self.filter.into();
+        match self.mode {
+            ResizeMode::Fit if !fits => image.resize(max_width, max_height, filter),
+            ResizeMode::Fill if !fits => {
+                image.resize_to_fill(max_width.min(width), max_height.min(height), filter)
+            }
+            ResizeMode::Exact if (width, height) != (max_width, max_height) => {
+                image.resize_exact(max_width, max_height, filter)
+            }
+            _ => image,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(resize: Resize, width: u32, height: u32) -> (u32, u32) {
+        resize
+            .apply(DynamicImage::new_rgb8(width, height))
+            .dimensions()
+    }
+
+    #[test]
+    fn test_fit() {
+        let resize = Resize::new(Some(100), Some(100));
+        assert_eq!(apply(resize, 400, 200), (100, 50));
+        assert_eq!(apply(resize, 50, 20), (50, 20));
+        assert_eq!(apply(Resize::new(None, Some(100)), 400, 200), (200, 100));
+    }
+
+    #[test]
+    fn test_fill() {
+        let resize = Resize::new(Some(100), Some(100)).with_mode(ResizeMode::Fill);
+        assert_eq!(apply(resize, 400, 200), (100, 100));
+        assert_eq!(apply(resize, 400, 50), (100, 50));
+        assert_eq!(apply(resize, 50, 20), (50, 20));
+    }
+
+    #[test]
+    fn test_exact() {
+        let resize = Resize::new(Some(100), Some(100))
+            .with_mode(ResizeMode::Exact)
+            .with_filter(ResizeFilter::Nearest);
+        assert_eq!(apply(resize, 400, 200), (100, 100));
+        assert_eq!(apply(resize, 50, 20), (100, 100));
+        assert_eq!(
+            apply(
+                Resize::new(Some(10), None).with_mode(ResizeMode::Exact),
+                40,
+                20
+            ),
+            (10, 20)
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("FILL".parse::<ResizeMode>().unwrap(), ResizeMode::Fill);
+        assert_eq!(
+            "catmull-rom".parse::<ResizeFilter>().unwrap(),
+            ResizeFilter::CatmullRom
+        );
+        assert!("stretch".parse::<ResizeMode>().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use image::{DynamicImage, GrayImage};
+
+/// Side of the square windows the statistics are computed over
+const WINDOW: u32 = 8;
+/// Distance between neighbouring windows
+const STEP: usize = 4;
+/// Stabilizes the luminance term for dark windows
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+/// Stabilizes the contrast term for flat windows
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Mean structural similarity of the luma channels of two images of the same size,
+/// 1.0 meaning identical
+pub(crate) fn ssim(reference: &DynamicImage, distorted: &DynamicImage) -> f64 {
+    let reference = reference.to_luma8();
+    let distorted = distorted.to_luma8();
+    let (width, height) = reference.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let window_width = WINDOW.min(width);
+    let window_height = WINDOW.min(height);
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - window_height).step_by(STEP) {
+        for x in (0..=width - window_width).step_by(STEP) {
+            total += window_ssim(&reference, &distorted, x, y, window_width, window_height);
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn window_ssim(
+    reference: &GrayImage,
+    distorted: &GrayImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> f64 {
+    let count = (width * height) as f64;
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for dy in 0..height {
+        for dx in 0..width {
+            let a = reference.get_pixel(x + dx, y + dy)[0] as f64;
+            let b = distorted.get_pixel(x + dx, y + dy)[0] as f64;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+    let mean_a = sum_a / count;
+    let mean_b = sum_b / count;
+    let variance_a = sum_aa / count - mean_a * mean_a;
+    let variance_b = sum_bb / count - mean_b * mean_b;
+    let covariance = sum_ab / count - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 48, |x, y| {
+            image::Luma([(x * 3 + y) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_identical() {
+        let image = gradient();
+        assert!((ssim(&image, &image) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_distorted() {
+        let image = gradient();
+        let mut noisy = image.to_luma8();
+        for (i, pixel) in noisy.pixels_mut().enumerate() {
+            pixel[0] = pixel[0].saturating_add(if i % 3 == 0 { 40 } else { 0 });
+        }
+        let noisy = DynamicImage::ImageLuma8(noisy);
+        let flat = DynamicImage::new_luma8(64, 48);
+        let noisy_score = ssim(&image, &noisy);
+        assert!(noisy_score < 0.99);
+        assert!(ssim(&image, &flat) < noisy_score);
+    }
+
+    #[test]
+    fn test_tiny_image() {
+        let image = DynamicImage::new_luma8(3, 2);
+        assert!((ssim(&image, &image) - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,62 @@
+//! Processor implemented outside the crate, as users of the registry write them
+
+use img_processor::{
+    ColorType, ImageProcessor, ImageProcessorError, ImageProcessorFactory, ImageSource,
+    OutputFormat, ProcessorRegistry, Quality, Registration, ShrinkOptions, ShrinkOutcome,
+    ShrinkReport, Speed,
+};
+use std::{fs, io::Write, path::Path, time::Duration};
+
+/// Processor of a made-up format storing the quality and speed in front of the image data
+struct TaggingProcessor(ImageSource);
+
+impl ImageProcessor for TaggingProcessor {
+    fn shrink_into(
+        &self,
+        output: &mut dyn Write,
+        options: &ShrinkOptions,
+    ) -> Result<ShrinkReport, ImageProcessorError> {
+        let input = self.0.read()?;
+        let data = &input[b"TAGGED".len()..];
+        let tag = [options.quality.value(), options.speed.value()];
+        output
+            .write_all(&tag)
+            .and_then(|()| output.write_all(data))
+            .map_err(|source| ImageProcessorError::WriteOutput { path: None, source })?;
+        let report = ShrinkReport::new(
+            input.len() as u64,
+            (tag.len() + data.len()) as u64,
+            (4, 2),
+            ColorType::Rgb8,
+            OutputFormat::Png,
+            options.quality,
+        )
+        .with_output_dimensions(2, 1)
+        .with_ssim(0.5)
+        .with_times(Duration::from_millis(1), Duration::from_millis(2))
+        .with_outcome(ShrinkOutcome::Written);
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_custom_processor() {
+    let registry = ProcessorRegistry::new().with(
+        Registration::new(|source| Ok(Box::new(TaggingProcessor(source)))).with_magic(b"TAGGED"),
+    );
+    let options =
+        ShrinkOptions::new(Quality::try_from(42).unwrap()).with_speed(Speed::try_from(7).unwrap());
+    let processor = registry.process_bytes(b"TAGGEDdata").unwrap();
+
+    let output_path = Path::new("/tmp/img-compactor-test-custom-processor.png");
+    fs::remove_file(output_path).ok();
+    let report = processor.shrink_to(output_path, &options).unwrap();
+    assert_eq!(fs::read(output_path).unwrap(), b"\x2a\x07data");
+    assert_eq!(report.quality, options.quality);
+    assert_eq!(report.input_dimensions, (4, 2));
+    assert_eq!(report.output_dimensions, (2, 1));
+    assert_eq!(report.ssim, Some(0.5));
+    assert_eq!(report.encode_time, Duration::from_millis(2));
+    assert_eq!(report.written_size(), 6);
+    fs::remove_file(output_path).ok();
+}
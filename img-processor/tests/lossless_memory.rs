@@ -0,0 +1,73 @@
+//! Peak memory of lossless JPEG optimization, measured by counting the allocations
+
+use img_processor::{
+    DefaultImageProcessorFactory, ImageProcessorError, ImageProcessorFactory, Limits, Quality,
+    ShrinkOptions,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// System allocator keeping track of the largest number of bytes allocated at once
+struct PeakAllocator {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = self.current.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        self.peak.fetch_max(current, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.current.fetch_sub(layout.size(), Ordering::SeqCst);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator {
+    current: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+#[test]
+fn test_coefficient_memory() {
+    // A small JPEG whose frame header claims 2048x2048 pixels, whose 256x256 blocks of
+    // brightness and 128x128 blocks of each color take 128 bytes of coefficients each
+    let mut input = Vec::new();
+    jpeg_encoder::Encoder::new(&mut input, 50)
+        .encode(&[0; 3 * 64 * 64], 64, 64, jpeg_encoder::ColorType::Rgb)
+        .unwrap();
+    let sof = input.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+    input[sof + 5..sof + 9].copy_from_slice(&[0x08, 0x00, 0x08, 0x00]);
+    let coefficients = (256 * 256 + 2 * 128 * 128) * 128;
+
+    let factory = DefaultImageProcessorFactory::default();
+    let processor = factory.process_bytes(&input).unwrap();
+    let lossless = |max_alloc| {
+        let options = ShrinkOptions::new(Quality::try_from(50).unwrap())
+            .with_lossless(true)
+            .with_limits(Limits::default().with_max_alloc(max_alloc));
+        let mut output = Vec::new();
+        let baseline = ALLOCATOR.current.load(Ordering::SeqCst);
+        ALLOCATOR.peak.store(baseline, Ordering::SeqCst);
+        let result = processor.shrink_into(&mut output, &options);
+        (result, ALLOCATOR.peak.load(Ordering::SeqCst) - baseline)
+    };
+    let (result, _) = lossless(coefficients as u64 - 1);
+    assert!(matches!(
+        result,
+        Err(ImageProcessorError::LimitExceeded { .. })
+    ));
+    // Within the limit, the coefficients are the only large allocation besides the output
+    let (result, peak) = lossless(coefficients as u64);
+    assert!(!matches!(
+        result,
+        Err(ImageProcessorError::LimitExceeded { .. })
+    ));
+    assert!(peak < coefficients * 5 / 4, "{} bytes allocated", peak);
+}